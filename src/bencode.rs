@@ -1,6 +1,8 @@
 use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum BencodeValue {
     Integer(i64),
     Bytes(Vec<u8>),
@@ -21,6 +23,43 @@ impl From<i64> for BencodeValue {
 }
 
 impl BencodeValue {
+    pub fn as_integer(&self) -> Option<i64> {
+        match self {
+            BencodeValue::Integer(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            BencodeValue::Bytes(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        self.as_bytes().and_then(|b| std::str::from_utf8(b).ok())
+    }
+
+    pub fn as_list(&self) -> Option<&Vec<BencodeValue>> {
+        match self {
+            BencodeValue::List(l) => Some(l),
+            _ => None,
+        }
+    }
+
+    pub fn as_map(&self) -> Option<&BTreeMap<Vec<u8>, BencodeValue>> {
+        match self {
+            BencodeValue::Map(m) => Some(m),
+            _ => None,
+        }
+    }
+
+    /// Looks up `key` if this is a map.
+    pub fn get(&self, key: &[u8]) -> Option<&BencodeValue> {
+        self.as_map().and_then(|m| m.get(key))
+    }
+
     fn collect_bytes(bytes: &[u8], segments: &mut Vec<u8>) {
         segments.extend_from_slice(format!("{}:", bytes.len()).as_bytes());
         segments.extend_from_slice(bytes);
@@ -55,6 +94,219 @@ impl BencodeValue {
         self.collect_segments(&mut ret);
         ret
     }
+
+    /// Strictly decodes a complete bencoded buffer. Only the canonical
+    /// encoding produced by `serialize` is accepted, so decoding and
+    /// re-serializing a value always gives back the same bytes.
+    pub fn deserialize(data: &[u8]) -> Result<BencodeValue, DecodeError> {
+        let mut decoder = Decoder { data, pos: 0 };
+        let value = decoder.value(0)?;
+        if decoder.pos != data.len() {
+            return Err(decoder.error(DecodeErrorKind::TrailingData));
+        }
+        Ok(value)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeErrorKind {
+    UnexpectedEof,
+    UnexpectedByte(u8),
+    LeadingZero,
+    NegativeZero,
+    IntegerOverflow,
+    NonStringKey,
+    UnsortedKey,
+    DuplicateKey,
+    NestingTooDeep,
+    TrailingData,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeError {
+    // Byte offset into the input where the problem was detected
+    pub offset: usize,
+    pub kind: DecodeErrorKind,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let what = match self.kind {
+            DecodeErrorKind::UnexpectedEof => "unexpected end of data".into(),
+            DecodeErrorKind::UnexpectedByte(b) => {
+                format!("unexpected byte 0x{:02x}", b)
+            }
+            DecodeErrorKind::LeadingZero => "number has leading zero".into(),
+            DecodeErrorKind::NegativeZero => "negative zero".into(),
+            DecodeErrorKind::IntegerOverflow => "number out of range".into(),
+            DecodeErrorKind::NonStringKey => "dict key is not a string".into(),
+            DecodeErrorKind::UnsortedKey => "dict keys are not sorted".into(),
+            DecodeErrorKind::DuplicateKey => "duplicate dict key".into(),
+            DecodeErrorKind::NestingTooDeep => "nesting too deep".into(),
+            DecodeErrorKind::TrailingData => "trailing data".into(),
+        };
+        write!(f, "bencode: {} at byte {}", what, self.offset)
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+// Deep enough for any sane torrent, shallow enough not to blow the stack.
+const MAX_DEPTH: usize = 512;
+
+struct Decoder<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Decoder<'_> {
+    fn error(&self, kind: DecodeErrorKind) -> DecodeError {
+        DecodeError {
+            offset: self.pos,
+            kind,
+        }
+    }
+
+    fn peek(&self) -> Result<u8, DecodeError> {
+        self.data
+            .get(self.pos)
+            .copied()
+            .ok_or_else(|| self.error(DecodeErrorKind::UnexpectedEof))
+    }
+
+    fn expect(&mut self, b: u8) -> Result<(), DecodeError> {
+        let c = self.peek()?;
+        if c != b {
+            return Err(self.error(DecodeErrorKind::UnexpectedByte(c)));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    // Parses the digits of a non-negative number, rejecting leading zeros.
+    fn digits(&mut self) -> Result<u64, DecodeError> {
+        let start = self.pos;
+        let first = self.peek()?;
+        if !first.is_ascii_digit() {
+            return Err(self.error(DecodeErrorKind::UnexpectedByte(first)));
+        }
+        let mut n = 0u64;
+        while let Some(&c) = self.data.get(self.pos) {
+            if !c.is_ascii_digit() {
+                break;
+            }
+            if self.pos > start && first == b'0' {
+                return Err(DecodeError {
+                    offset: start,
+                    kind: DecodeErrorKind::LeadingZero,
+                });
+            }
+            n = n
+                .checked_mul(10)
+                .and_then(|n| n.checked_add((c - b'0') as u64))
+                .ok_or(DecodeError {
+                    offset: start,
+                    kind: DecodeErrorKind::IntegerOverflow,
+                })?;
+            self.pos += 1;
+        }
+        Ok(n)
+    }
+
+    fn integer(&mut self) -> Result<i64, DecodeError> {
+        self.expect(b'i')?;
+        let start = self.pos;
+        let negative = self.peek()? == b'-';
+        if negative {
+            self.pos += 1;
+        }
+        let n = self.digits()?;
+        self.expect(b'e')?;
+        let overflow = DecodeError {
+            offset: start,
+            kind: DecodeErrorKind::IntegerOverflow,
+        };
+        if negative {
+            if n == 0 {
+                return Err(DecodeError {
+                    offset: start,
+                    kind: DecodeErrorKind::NegativeZero,
+                });
+            }
+            if n == i64::MIN.unsigned_abs() {
+                return Ok(i64::MIN);
+            }
+            i64::try_from(n).map(|n| -n).map_err(|_| overflow)
+        } else {
+            i64::try_from(n).map_err(|_| overflow)
+        }
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>, DecodeError> {
+        let len = self.digits()?;
+        self.expect(b':')?;
+        if len > (self.data.len() - self.pos) as u64 {
+            return Err(DecodeError {
+                offset: self.data.len(),
+                kind: DecodeErrorKind::UnexpectedEof,
+            });
+        }
+        let ret = self.data[self.pos..self.pos + len as usize].to_vec();
+        self.pos += len as usize;
+        Ok(ret)
+    }
+
+    fn value(&mut self, depth: usize) -> Result<BencodeValue, DecodeError> {
+        if depth > MAX_DEPTH {
+            return Err(self.error(DecodeErrorKind::NestingTooDeep));
+        }
+        match self.peek()? {
+            b'i' => Ok(BencodeValue::Integer(self.integer()?)),
+            b'0'..=b'9' => Ok(BencodeValue::Bytes(self.bytes()?)),
+            b'l' => {
+                self.pos += 1;
+                let mut list = vec![];
+                while self.peek()? != b'e' {
+                    list.push(self.value(depth + 1)?);
+                }
+                self.pos += 1;
+                Ok(BencodeValue::List(list))
+            }
+            b'd' => {
+                self.pos += 1;
+                let mut map = BTreeMap::new();
+                let mut last_key: Option<Vec<u8>> = None;
+                while self.peek()? != b'e' {
+                    let key_offset = self.pos;
+                    if !self.peek()?.is_ascii_digit() {
+                        return Err(self.error(DecodeErrorKind::NonStringKey));
+                    }
+                    let key = self.bytes()?;
+                    if let Some(last) = &last_key {
+                        let kind = if *last == key {
+                            Some(DecodeErrorKind::DuplicateKey)
+                        } else if *last > key {
+                            Some(DecodeErrorKind::UnsortedKey)
+                        } else {
+                            None
+                        };
+                        if let Some(kind) = kind {
+                            return Err(DecodeError {
+                                offset: key_offset,
+                                kind,
+                            });
+                        }
+                    }
+                    let value = self.value(depth + 1)?;
+                    last_key = Some(key.clone());
+                    map.insert(key, value);
+                }
+                self.pos += 1;
+                Ok(BencodeValue::Map(map))
+            }
+            c => Err(self.error(DecodeErrorKind::UnexpectedByte(c))),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -124,3 +376,142 @@ mod serialization_test {
         );
     }
 }
+
+#[cfg(test)]
+mod deserialization_test {
+    use super::{BencodeValue, DecodeError, DecodeErrorKind};
+    use std::collections::BTreeMap;
+
+    fn err(
+        offset: usize,
+        kind: DecodeErrorKind,
+    ) -> Result<BencodeValue, DecodeError> {
+        Err(DecodeError { offset, kind })
+    }
+
+    #[test]
+    fn integer() {
+        assert_eq!(BencodeValue::deserialize(b"i0e"), Ok(0.into()));
+        assert_eq!(BencodeValue::deserialize(b"i42e"), Ok(42.into()));
+        assert_eq!(BencodeValue::deserialize(b"i-42e"), Ok((-42).into()));
+        assert_eq!(
+            BencodeValue::deserialize(b"i9223372036854775807e"),
+            Ok(i64::MAX.into())
+        );
+        assert_eq!(
+            BencodeValue::deserialize(b"i-9223372036854775808e"),
+            Ok(i64::MIN.into())
+        );
+        assert_eq!(
+            BencodeValue::deserialize(b"i9223372036854775808e"),
+            err(1, DecodeErrorKind::IntegerOverflow)
+        );
+        assert_eq!(
+            BencodeValue::deserialize(b"i-0e"),
+            err(1, DecodeErrorKind::NegativeZero)
+        );
+        assert_eq!(
+            BencodeValue::deserialize(b"i03e"),
+            err(1, DecodeErrorKind::LeadingZero)
+        );
+        assert_eq!(
+            BencodeValue::deserialize(b"i-03e"),
+            err(2, DecodeErrorKind::LeadingZero)
+        );
+        assert_eq!(
+            BencodeValue::deserialize(b"ie"),
+            err(1, DecodeErrorKind::UnexpectedByte(b'e'))
+        );
+        assert_eq!(
+            BencodeValue::deserialize(b"i4"),
+            err(2, DecodeErrorKind::UnexpectedEof)
+        );
+    }
+
+    #[test]
+    fn bytes() {
+        assert_eq!(
+            BencodeValue::deserialize(b"0:"),
+            Ok(BencodeValue::Bytes(vec![]))
+        );
+        assert_eq!(BencodeValue::deserialize(b"3:foo"), Ok("foo".into()));
+        assert_eq!(
+            BencodeValue::deserialize(b"03:foo"),
+            err(0, DecodeErrorKind::LeadingZero)
+        );
+        assert_eq!(
+            BencodeValue::deserialize(b"4:foo"),
+            err(5, DecodeErrorKind::UnexpectedEof)
+        );
+        assert_eq!(
+            BencodeValue::deserialize(b"3foo"),
+            err(1, DecodeErrorKind::UnexpectedByte(b'f'))
+        );
+    }
+
+    #[test]
+    fn list_and_map() {
+        let mut map = BTreeMap::new();
+        map.insert(b"bar".to_vec(), BencodeValue::Map(BTreeMap::new()));
+        map.insert(b"buz".to_vec(), BencodeValue::List(vec![]));
+        map.insert(b"foo".to_vec(), BencodeValue::Integer(42));
+        assert_eq!(
+            BencodeValue::deserialize(b"d3:barde3:buzle3:fooi42ee"),
+            Ok(BencodeValue::Map(map))
+        );
+        assert_eq!(
+            BencodeValue::deserialize(b"li42e3:fooe"),
+            Ok(BencodeValue::List(vec![42.into(), "foo".into()]))
+        );
+        assert_eq!(
+            BencodeValue::deserialize(b"d3:fooi1e3:bari2ee"),
+            err(9, DecodeErrorKind::UnsortedKey)
+        );
+        assert_eq!(
+            BencodeValue::deserialize(b"d3:fooi1e3:fooi2ee"),
+            err(9, DecodeErrorKind::DuplicateKey)
+        );
+        assert_eq!(
+            BencodeValue::deserialize(b"di1ei2ee"),
+            err(1, DecodeErrorKind::NonStringKey)
+        );
+        assert_eq!(
+            BencodeValue::deserialize(b"l"),
+            err(1, DecodeErrorKind::UnexpectedEof)
+        );
+        assert_eq!(
+            BencodeValue::deserialize(b"x"),
+            err(0, DecodeErrorKind::UnexpectedByte(b'x'))
+        );
+    }
+
+    #[test]
+    fn trailing_data() {
+        assert_eq!(
+            BencodeValue::deserialize(b"i1ei2e"),
+            err(3, DecodeErrorKind::TrailingData)
+        );
+        assert_eq!(
+            BencodeValue::deserialize(b""),
+            err(0, DecodeErrorKind::UnexpectedEof)
+        );
+    }
+
+    #[test]
+    fn nesting() {
+        let mut deep = vec![b'l'; 10000];
+        deep.extend(vec![b'e'; 10000]);
+        assert_eq!(
+            BencodeValue::deserialize(&deep).unwrap_err().kind,
+            DecodeErrorKind::NestingTooDeep
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        let data: &[u8] = b"d8:announce3:foo4:infod6:lengthi9e4:name1:a\
+                             12:piece lengthi16384e6:pieces0:ee";
+        let value = BencodeValue::deserialize(data).unwrap();
+        assert_eq!(value.serialize(), data);
+    }
+}
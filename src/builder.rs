@@ -0,0 +1,661 @@
+use crate::bencode::BencodeValue;
+use crate::dirwalker::{read_file_list, SymlinkMode, WalkOptions, WalkedDir};
+use crate::error::{Error, Result};
+use crate::infohash::InfoHashes;
+use crate::magnet;
+use crate::piece_size::{
+    check_piece_size, PieceSizePolicy, MAX_COMPATIBLE_PIECE_SIZE,
+};
+use crate::progress::ProgressIndicator;
+use crate::reader::IoMode;
+use crate::torrent_meta::{
+    ExtraKeys, FileOptions, PadStyle, PaddingOptions,
+    TorrentMetadata as TorrentMetadataV1,
+};
+use crate::torrent_meta_v2::TorrentMetadata as TorrentMetadataV2;
+
+use indicatif::HumanBytes;
+use log::*;
+use std::collections::BTreeMap;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Default "created by" of new torrents.
+pub const CREATED_BY: &str =
+    concat!("mktorrent-rs/", env!("CARGO_PKG_VERSION"));
+
+// Keys written by the builder itself, which can't be set as custom keys.
+const RESERVED_ROOT_KEYS: &[&str] = &[
+    "announce",
+    "announce-list",
+    "comment",
+    "created by",
+    "creation date",
+    "info",
+    "nodes",
+    "piece layers",
+    "url-list",
+];
+const RESERVED_INFO_KEYS: &[&str] = &[
+    "attr",
+    "file tree",
+    "files",
+    "length",
+    "meta version",
+    "name",
+    "piece length",
+    "pieces",
+    "private",
+    "sha1",
+    "symlink path",
+];
+
+// `epoch` is the value of SOURCE_DATE_EPOCH, used if set for reproducible
+// builds. Falls back to the current time.
+fn creation_date_from(epoch: Option<String>) -> i64 {
+    if let Some(epoch) = epoch {
+        match epoch.parse::<i64>() {
+            Ok(t) => return t,
+            Err(_) => warn!("Ignoring invalid SOURCE_DATE_EPOCH {:?}", epoch),
+        }
+    }
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+/// A finished torrent file.
+pub struct Torrent {
+    root: BencodeValue,
+}
+
+impl Torrent {
+    pub fn as_bencode(&self) -> &BencodeValue {
+        &self.root
+    }
+
+    pub fn into_bencode(self) -> BencodeValue {
+        self.root
+    }
+
+    /// The content of the .torrent file.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.root.serialize()
+    }
+
+    pub fn info_hashes(&self) -> InfoHashes {
+        InfoHashes::from_torrent(&self.root).unwrap()
+    }
+
+    pub fn magnet_uri(&self) -> String {
+        magnet::magnet_uri(&self.root).unwrap()
+    }
+}
+
+/// Collects the options for a new torrent, then walks and hashes the input.
+pub struct TorrentBuilder {
+    inputs: Vec<PathBuf>,
+    // Virtual root folder holding all inputs
+    name: Option<String>,
+    walk_options: WalkOptions,
+    // Manifest of files to take instead of walking the input
+    files_from: Option<PathBuf>,
+    announces: Vec<Vec<String>>,
+    nodes: Vec<(String, u16)>,
+    webseeds: Vec<String>,
+    private: bool,
+    comment: Option<String>,
+    created_by: Option<String>,
+    creation_date: Option<i64>,
+    // Info dict "source", kept apart to catch a second one in `info_key`
+    source: Option<String>,
+    // Custom keys of the root and info dicts
+    extra_keys: ExtraKeys,
+    piece_size: Option<u64>,
+    piece_size_policy: PieceSizePolicy,
+    write_v1: bool,
+    write_v2: bool,
+    padding: bool,
+    padding_options: PaddingOptions,
+    file_options: FileOptions,
+    threads: u32,
+    io_mode: IoMode,
+    show_progress: bool,
+}
+
+impl TorrentBuilder {
+    /// Starts a hybrid (v1 + v2), padded torrent of the file or folder
+    /// `input`.
+    pub fn new<P: AsRef<Path>>(input: P) -> Self {
+        TorrentBuilder {
+            inputs: vec![input.as_ref().to_path_buf()],
+            name: None,
+            walk_options: WalkOptions::default(),
+            files_from: None,
+            announces: vec![],
+            nodes: vec![],
+            webseeds: vec![],
+            private: false,
+            comment: None,
+            created_by: Some(CREATED_BY.into()),
+            creation_date: Some(creation_date_from(
+                env::var("SOURCE_DATE_EPOCH").ok(),
+            )),
+            source: None,
+            extra_keys: ExtraKeys::default(),
+            piece_size: None,
+            piece_size_policy: PieceSizePolicy::default(),
+            write_v1: true,
+            write_v2: true,
+            padding: true,
+            padding_options: PaddingOptions::default(),
+            file_options: FileOptions::default(),
+            threads: 1,
+            io_mode: IoMode::default(),
+            show_progress: false,
+        }
+    }
+
+    /// Skips files and folders matching a gitignore-style pattern.
+    pub fn exclude<S: Into<String>>(mut self, pattern: S) -> Self {
+        self.walk_options.exclude.push(pattern.into());
+        self
+    }
+
+    /// Reads exclude patterns from a file, one per line.
+    pub fn exclude_from<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.walk_options
+            .exclude_from
+            .push(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Keeps only files matching one of the include patterns. A file input
+    /// with include patterns is rejected by `walk`.
+    pub fn include<S: Into<String>>(mut self, pattern: S) -> Self {
+        self.walk_options.include.push(pattern.into());
+        self
+    }
+
+    /// Adds another file or folder. Several inputs need a `name`.
+    pub fn input<P: AsRef<Path>>(mut self, input: P) -> Self {
+        self.inputs.push(input.as_ref().to_path_buf());
+        self
+    }
+
+    /// Puts all inputs under a virtual root folder called `name`, which
+    /// becomes the torrent name.
+    pub fn name<S: Into<String>>(mut self, name: S) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Takes the files listed in `path` instead of walking the input folder,
+    /// see `read_file_list`. Exclude and include patterns don't apply.
+    pub fn files_from<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.files_from = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Whether to honor `.torrentignore` in the input folder. On by default.
+    pub fn torrentignore(mut self, enabled: bool) -> Self {
+        self.walk_options.torrentignore = enabled;
+        self
+    }
+
+    /// Whether to keep files and folders whose name starts with a dot.
+    pub fn include_hidden(mut self, enabled: bool) -> Self {
+        self.walk_options.include_hidden = enabled;
+        self
+    }
+
+    /// How to handle symlinks in the input folder. Followed by default.
+    pub fn symlinks(mut self, mode: SymlinkMode) -> Self {
+        self.walk_options.symlinks = mode;
+        self
+    }
+
+    /// Adds a tier of tracker URLs. BEP12
+    pub fn announce_tier(mut self, tier: Vec<String>) -> Self {
+        self.announces.push(tier);
+        self
+    }
+
+    /// Adds a tier holding a single tracker URL.
+    pub fn announce<S: Into<String>>(self, url: S) -> Self {
+        self.announce_tier(vec![url.into()])
+    }
+
+    /// Adds a DHT node. BEP5
+    pub fn node<S: Into<String>>(mut self, host: S, port: u16) -> Self {
+        self.nodes.push((host.into(), port));
+        self
+    }
+
+    /// Adds a WebSeed URL. BEP19
+    pub fn webseed<S: Into<String>>(mut self, url: S) -> Self {
+        self.webseeds.push(url.into());
+        self
+    }
+
+    /// Marks the torrent as private. BEP27
+    pub fn private(mut self, private: bool) -> Self {
+        self.private = private;
+        self
+    }
+
+    /// Free-form comment of the torrent.
+    pub fn comment<S: Into<String>>(mut self, comment: S) -> Self {
+        self.comment = Some(comment.into());
+        self
+    }
+
+    /// Name of the program creating the torrent, `CREATED_BY` by default.
+    /// None to leave it out.
+    pub fn created_by(mut self, created_by: Option<String>) -> Self {
+        self.created_by = created_by;
+        self
+    }
+
+    /// Creation time in seconds since the Unix epoch. Defaults to
+    /// `SOURCE_DATE_EPOCH` if set, the current time otherwise. None to leave
+    /// it out.
+    pub fn creation_date(mut self, creation_date: Option<i64>) -> Self {
+        self.creation_date = creation_date;
+        self
+    }
+
+    /// Sets `source` in the info dict, which private trackers use to make
+    /// the info hash unique to their site.
+    pub fn source<S: Into<String>>(mut self, source: S) -> Self {
+        self.source = Some(source.into());
+        self
+    }
+
+    /// Adds a custom key to the info dict. Keys generated by the builder
+    /// are rejected by `check`.
+    pub fn info_key<S: Into<String>>(
+        mut self,
+        key: S,
+        value: BencodeValue,
+    ) -> Self {
+        self.extra_keys.info.insert(key.into().into_bytes(), value);
+        self
+    }
+
+    /// Adds a custom key to the root dict. Keys generated by the builder
+    /// are rejected by `check`.
+    pub fn root_key<S: Into<String>>(
+        mut self,
+        key: S,
+        value: BencodeValue,
+    ) -> Self {
+        self.extra_keys.root.insert(key.into().into_bytes(), value);
+        self
+    }
+
+    /// Bytes of each piece. Selected automatically if not set.
+    pub fn piece_size(mut self, piece_size: u64) -> Self {
+        self.piece_size = Some(piece_size);
+        self
+    }
+
+    /// How to select the piece size if it's not set with `piece_size`.
+    pub fn piece_size_policy(mut self, policy: PieceSizePolicy) -> Self {
+        self.piece_size_policy = policy;
+        self
+    }
+
+    /// Whether to generate BEP3 (BitTorrent v1) metadata.
+    pub fn v1(mut self, enabled: bool) -> Self {
+        self.write_v1 = enabled;
+        self
+    }
+
+    /// Whether to generate BEP52 (BitTorrent v2) metadata.
+    pub fn v2(mut self, enabled: bool) -> Self {
+        self.write_v2 = enabled;
+        self
+    }
+
+    /// Whether to align files to pieces with BEP47 padding files.
+    pub fn padding(mut self, enabled: bool) -> Self {
+        self.padding = enabled;
+        self
+    }
+
+    /// Whether to pad the last data file too. Some clients expect the
+    /// torrent to end with a padding file.
+    pub fn pad_last(mut self, enabled: bool) -> Self {
+        self.padding_options.pad_last = enabled;
+        self
+    }
+
+    /// Only aligns files larger than `min_size` bytes to pieces, smaller
+    /// ones share pieces with their neighbours. Needs v1-only output, as
+    /// v2 requires every file to be aligned.
+    pub fn pad_min_size(mut self, min_size: u64) -> Self {
+        self.padding_options.min_size = Some(min_size);
+        self
+    }
+
+    /// Naming of the padding files in the v1 file list. `.pad/<N>` by
+    /// default.
+    pub fn pad_style(mut self, style: PadStyle) -> Self {
+        self.padding_options.style = style;
+        self
+    }
+
+    /// Whether to mark executable and hidden files with BEP47 attributes.
+    pub fn preserve_attrs(mut self, enabled: bool) -> Self {
+        self.file_options.preserve_attrs = enabled;
+        self
+    }
+
+    /// Whether to store the SHA-1 of each file in the v1 file list. BEP47
+    pub fn file_sha1(mut self, enabled: bool) -> Self {
+        self.file_options.sha1 = enabled;
+        self
+    }
+
+    /// Number of hashing threads.
+    pub fn threads(mut self, threads: u32) -> Self {
+        self.threads = threads;
+        self
+    }
+
+    /// How the files are read while hashing. Defaults to mmap.
+    pub fn io(mut self, mode: IoMode) -> Self {
+        self.io_mode = mode;
+        self
+    }
+
+    /// Shows progress bars on the terminal in `build`.
+    pub fn show_progress(mut self, show: bool) -> Self {
+        self.show_progress = show;
+        self
+    }
+
+    pub fn check(&self) -> Result<()> {
+        let invalid = |s: &str| Err(Error::InvalidOption(s.into()));
+        if !self.write_v1 && !self.write_v2 {
+            return invalid("At least one bep3/bep52");
+        }
+        if self.announces.iter().all(Vec::is_empty) && self.nodes.is_empty() {
+            return invalid("Please specify tracker/node URL");
+        }
+        if let Some(x) = self.piece_size {
+            check_piece_size(x)?;
+        }
+        if self.file_options.sha1 && !self.write_v1 {
+            return invalid("file_sha1 needs bep3 metadata");
+        }
+        let reserved = |keys: &BTreeMap<Vec<u8>, BencodeValue>,
+                        reserved: &[&str]| {
+            keys.keys()
+                .find(|k| reserved.iter().any(|r| r.as_bytes() == &k[..]))
+                .map(|k| String::from_utf8_lossy(k).into_owned())
+        };
+        if let Some(k) = reserved(&self.extra_keys.info, RESERVED_INFO_KEYS) {
+            return Err(Error::InvalidOption(format!(
+                "Reserved info key: {}",
+                k
+            )));
+        }
+        if let Some(k) = reserved(&self.extra_keys.root, RESERVED_ROOT_KEYS) {
+            return Err(Error::InvalidOption(format!(
+                "Reserved root key: {}",
+                k
+            )));
+        }
+        if self.source.is_some()
+            && self.extra_keys.info.contains_key(&b"source"[..])
+        {
+            return invalid("source is set both by source and info_key");
+        }
+        if self.inputs.len() > 1 && self.name.is_none() {
+            return invalid("Multiple inputs need a name");
+        }
+        if self.files_from.is_some() && self.inputs.len() > 1 {
+            return invalid("files_from needs a single input");
+        }
+        if self.files_from.is_some()
+            && !(self.walk_options.exclude.is_empty()
+                && self.walk_options.exclude_from.is_empty()
+                && self.walk_options.include.is_empty())
+        {
+            return invalid("files_from is incompatible with patterns");
+        }
+        if self.threads < 1 {
+            return invalid("are you kidding me running with 0 thread?");
+        }
+        if !self.padding && self.write_v2 {
+            return invalid("no_padding is incompatible with bep52.");
+        }
+        if !self.padding && self.padding_options.pad_last {
+            return invalid("pad_last needs padding");
+        }
+        if self.padding_options.min_size.is_some() {
+            if !self.padding {
+                return invalid("pad_min_size needs padding");
+            }
+            if self.write_v2 {
+                return invalid("pad_min_size is incompatible with bep52.");
+            }
+        }
+        Ok(())
+    }
+
+    /// Walks the input, the first half of `build`.
+    pub fn walk(&self, progress: &mut ProgressIndicator) -> Result<WalkedDir> {
+        self.check()?;
+        let mut dirs = vec![];
+        for input in &self.inputs {
+            dirs.push(match &self.files_from {
+                Some(list) => {
+                    let list = read_file_list(list)?;
+                    WalkedDir::from_list(input, &list, progress)?
+                }
+                None => WalkedDir::walk(input, &self.walk_options, progress)?,
+            });
+        }
+        match &self.name {
+            Some(name) => WalkedDir::merge(name, dirs),
+            None => Ok(dirs.pop().unwrap()),
+        }
+    }
+
+    /// Hashes the walked input, the second half of `build`.
+    pub fn hash(
+        &self,
+        walked_dir: WalkedDir,
+        progress: &mut ProgressIndicator,
+    ) -> Result<Torrent> {
+        self.check()?;
+        let piece_size = self.piece_size.unwrap_or_else(|| {
+            self.piece_size_policy.select(
+                &walked_dir.files,
+                self.write_v1,
+                self.write_v2,
+                self.padding,
+            )
+        });
+        info!("Piece size: {}", piece_size);
+        if piece_size > MAX_COMPATIBLE_PIECE_SIZE {
+            warn!(
+                "Piece size {} is above {}, some clients may reject the torrent",
+                HumanBytes(piece_size),
+                HumanBytes(MAX_COMPATIBLE_PIECE_SIZE)
+            );
+        }
+        let mut extra_keys = self.extra_keys.clone();
+        if let Some(comment) = &self.comment {
+            extra_keys.root.insert(
+                b"comment".to_vec(),
+                BencodeValue::from(comment.as_str()),
+            );
+        }
+        if let Some(created_by) = &self.created_by {
+            extra_keys.root.insert(
+                b"created by".to_vec(),
+                BencodeValue::from(created_by.as_str()),
+            );
+        }
+        if let Some(source) = &self.source {
+            extra_keys.info.insert(
+                b"source".to_vec(),
+                BencodeValue::from(source.as_str()),
+            );
+        }
+        if let Some(date) = self.creation_date {
+            extra_keys
+                .root
+                .insert(b"creation date".to_vec(), BencodeValue::from(date));
+        }
+        // Files sharing pieces need the stream-based v1 hasher
+        let root = if !self.padding || self.padding_options.min_size.is_some() {
+            let mut torrent_meta = TorrentMetadataV1::new(
+                self.announces.clone(),
+                self.nodes.clone(),
+                self.private,
+                Some(piece_size),
+                self.webseeds.clone(),
+                self.file_options,
+                self.padding_options,
+                extra_keys,
+                walked_dir,
+            )?;
+            torrent_meta.hash(progress, self.threads, self.io_mode)
+        } else {
+            let mut v2 = TorrentMetadataV2::new(
+                self.announces.clone(),
+                self.nodes.clone(),
+                self.private,
+                Some(piece_size),
+                self.webseeds.clone(),
+                self.file_options,
+                self.padding_options,
+                extra_keys,
+                walked_dir,
+            )?;
+            v2.hash(
+                progress,
+                self.threads,
+                self.write_v1,
+                self.write_v2,
+                self.io_mode,
+            )
+        };
+        Ok(Torrent { root: root? })
+    }
+
+    pub fn build(&self) -> Result<Torrent> {
+        let mut progress = ProgressIndicator::new(!self.show_progress);
+        let walked_dir = self.walk(&mut progress)?;
+        self.hash(walked_dir, &mut progress)
+    }
+}
+
+#[cfg(test)]
+mod builder_test {
+    use super::*;
+    use crate::metainfo::MetaInfo;
+    use crate::test_util::TempDir;
+
+    fn build(builder: TorrentBuilder) -> MetaInfo {
+        let torrent = builder.announce("http://tracker/").build().unwrap();
+        MetaInfo::from_bytes(&torrent.to_bytes()).unwrap()
+    }
+
+    #[test]
+    fn outer_fields() {
+        let dir = TempDir::new("builder-outer-fields");
+        let input = dir.write("a", b"x");
+        let meta = build(TorrentBuilder::new(&input));
+        assert_eq!(meta.comment, None);
+        assert_eq!(meta.created_by.as_deref(), Some(CREATED_BY));
+        assert!(meta.creation_date.is_some());
+
+        let meta = build(
+            TorrentBuilder::new(&input)
+                .comment("hello")
+                .created_by(None)
+                .creation_date(Some(42)),
+        );
+        assert_eq!(meta.comment.as_deref(), Some("hello"));
+        assert_eq!(meta.created_by, None);
+        assert_eq!(meta.creation_date, Some(42));
+        let meta = build(TorrentBuilder::new(&input).creation_date(None));
+        assert_eq!(meta.creation_date, None);
+    }
+
+    #[test]
+    fn source_date_epoch() {
+        assert_eq!(creation_date_from(Some("1234".to_string())), 1234);
+        assert!(creation_date_from(Some("soon".to_string())) > 1234);
+        assert!(creation_date_from(None) > 1234);
+    }
+
+    #[test]
+    fn custom_keys() {
+        let dir = TempDir::new("builder-custom-keys");
+        let input = dir.write("a", b"x");
+        let torrent = TorrentBuilder::new(&input)
+            .announce("http://tracker/")
+            .source("X")
+            .info_key("x-info", BencodeValue::from(1))
+            .root_key("x-root", BencodeValue::from("r"))
+            .build()
+            .unwrap();
+        let root = torrent.as_bencode();
+        let info = root.get(b"info").unwrap();
+        assert_eq!(info.get(b"source"), Some(&BencodeValue::from("X")));
+        assert_eq!(info.get(b"x-info"), Some(&BencodeValue::from(1)));
+        assert_eq!(root.get(b"x-root"), Some(&BencodeValue::from("r")));
+
+        // Same as --info-key source=X
+        let same = TorrentBuilder::new(&input)
+            .announce("http://tracker/")
+            .info_key("source", BencodeValue::from("X"))
+            .info_key("x-info", BencodeValue::from(1))
+            .root_key("x-root", BencodeValue::from("r"))
+            .build()
+            .unwrap();
+        assert_eq!(same.as_bencode().get(b"info"), Some(info));
+    }
+
+    #[test]
+    fn rejected_keys() {
+        let builder = || TorrentBuilder::new("a").announce("http://tracker/");
+        let value = || BencodeValue::from("v");
+        assert!(builder().check().is_ok());
+        assert!(builder().info_key("pieces", value()).check().is_err());
+        assert!(builder().info_key("private", value()).check().is_err());
+        assert!(builder().root_key("info", value()).check().is_err());
+        assert!(builder().root_key("comment", value()).check().is_err());
+        assert!(builder()
+            .source("X")
+            .info_key("source", value())
+            .check()
+            .is_err());
+    }
+
+    #[test]
+    fn multiple_inputs() {
+        let dir = TempDir::new("builder-multiple-inputs");
+        let a = dir.write("a", b"x");
+        dir.write("b/c", b"yy");
+        let builder = TorrentBuilder::new(&a).input(dir.path().join("b"));
+        assert!(builder.check().is_err());
+        let meta = build(builder.name("r").v2(false).padding(false));
+        assert_eq!(meta.name, "r");
+        let files: Vec<_> = meta
+            .files
+            .iter()
+            .map(|f| (f.path.join("/"), f.length))
+            .collect();
+        assert_eq!(files, vec![("a".to_string(), 1), ("b/c".to_string(), 2)]);
+    }
+}
@@ -1,17 +1,285 @@
+use crate::error::{Error, Result};
 use crate::progress::ProgressIndicator;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
 use log::*;
-use std::fs::Metadata;
-use std::io;
+use std::collections::HashSet;
+use std::fs::{self, Metadata};
+use std::io::{self, Read};
 use std::path::{Component, Path, PathBuf};
-use walkdir::{DirEntry, WalkDir};
+use std::str::FromStr;
+use walkdir::WalkDir;
 
 pub struct DataFile {
-    pub entry: DirEntry,
+    pub path: PathBuf,
     pub path_components: Vec<String>,
     pub metadata: Metadata,
+    // Target of a preserved symlink, relative to the torrent root. BEP47
+    pub symlink_path: Option<Vec<String>>,
+}
+
+impl DataFile {
+    /// Bytes of data in the torrent. Preserved symlinks have none.
+    pub fn len(&self) -> u64 {
+        if self.symlink_path.is_some() {
+            0
+        } else {
+            self.metadata.len()
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// What to do with symbolic links found in the input folder.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymlinkMode {
+    /// Add the file or folder the link points to.
+    Follow,
+    /// Leave the link out.
+    Skip,
+    /// Add the link itself as a BEP47 symlink entry. Only works for links
+    /// pointing inside the input folder.
+    Preserve,
+}
+
+impl FromStr for SymlinkMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "follow" => Ok(SymlinkMode::Follow),
+            "skip" => Ok(SymlinkMode::Skip),
+            "preserve" => Ok(SymlinkMode::Preserve),
+            _ => Err(format!("Unknown symlink mode: {}", s)),
+        }
+    }
+}
+
+/// Name of the file in the input folder holding exclude patterns.
+pub const TORRENTIGNORE: &str = ".torrentignore";
+
+/// Selects the files picked up by `WalkedDir::walk`. Patterns use the
+/// gitignore syntax and are matched against the path relative to the input
+/// folder.
+#[derive(Debug, Clone)]
+pub struct WalkOptions {
+    /// Files and folders to skip.
+    pub exclude: Vec<String>,
+    /// Files to read more exclude patterns from, one per line.
+    pub exclude_from: Vec<PathBuf>,
+    /// If not empty, only files matching one of these are kept.
+    pub include: Vec<String>,
+    /// Read exclude patterns from `.torrentignore` in the input folder.
+    pub torrentignore: bool,
+    /// Keep files and folders whose name starts with a dot.
+    pub include_hidden: bool,
+    pub symlinks: SymlinkMode,
+}
+
+impl Default for WalkOptions {
+    fn default() -> Self {
+        WalkOptions {
+            exclude: vec![],
+            exclude_from: vec![],
+            include: vec![],
+            torrentignore: true,
+            include_hidden: false,
+            symlinks: SymlinkMode::Follow,
+        }
+    }
+}
+
+fn add_pattern(builder: &mut GitignoreBuilder, line: &str) -> Result<()> {
+    builder
+        .add_line(None, line)
+        .map_err(|e| Error::InvalidOption(format!("Invalid pattern: {}", e)))?;
+    Ok(())
+}
+
+fn add_pattern_file(builder: &mut GitignoreBuilder, path: &Path) -> Result<()> {
+    let content = fs::read_to_string(path).map_err(|e| Error::io(path, e))?;
+    for line in content.lines() {
+        add_pattern(builder, line)?;
+    }
+    Ok(())
+}
+
+fn build_matcher(builder: GitignoreBuilder) -> Result<Gitignore> {
+    builder
+        .build()
+        .map_err(|e| Error::InvalidOption(format!("Invalid pattern: {}", e)))
+}
+
+// Splits a path into UTF-8 components.
+fn path_components(path: &Path, full_path: &Path) -> Result<Vec<String>> {
+    let mut comp: Vec<String> = vec![];
+    for c in path.components() {
+        match c {
+            Component::Normal(os_str) => comp.push(
+                os_str
+                    .to_str()
+                    .ok_or_else(|| Error::NonUtf8Path(full_path.to_path_buf()))?
+                    .to_string(),
+            ),
+            _ => unreachable!("Invalid path: {}", path.display()),
+        }
+    }
+    Ok(comp)
+}
+
+// Target of the symlink `link` relative to `root`, None if it is dangling
+// or points outside of `root`. This is the link's own target, not the end
+// of a chain of links.
+fn symlink_target(link: &Path, root: &Path) -> Result<Option<Vec<String>>> {
+    let target = match fs::read_link(link) {
+        Ok(t) => t,
+        Err(_) => return Ok(None),
+    };
+    // Relative targets start from the folder holding the link.
+    let mut resolved = link.parent().unwrap().to_path_buf();
+    for c in target.components() {
+        match c {
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            Component::CurDir => (),
+            c => resolved.push(c),
+        }
+    }
+    if fs::metadata(&resolved).is_err() {
+        return Ok(None);
+    }
+    match resolved.strip_prefix(root) {
+        Ok(rel) if rel.components().next().is_some() => {
+            path_components(rel, link).map(Some)
+        }
+        _ => Ok(None),
+    }
+}
+
+// Drops preserved symlinks whose target is not in the torrent, e.g. an
+// excluded or hidden file. Targets may be a file or a folder holding one.
+fn drop_unlisted_targets(files: &mut Vec<DataFile>) {
+    loop {
+        let mut listed = HashSet::new();
+        for f in files.iter() {
+            for i in 2..=f.path_components.len() {
+                listed.insert(&f.path_components[1..i]);
+            }
+        }
+        let unlisted: Vec<usize> = files
+            .iter()
+            .enumerate()
+            .filter(|(_, f)| match &f.symlink_path {
+                Some(t) => !listed.contains(t.as_slice()),
+                None => false,
+            })
+            .map(|(i, _)| i)
+            .collect();
+        if unlisted.is_empty() {
+            return;
+        }
+        // Links to a dropped link are checked again.
+        for &i in unlisted.iter().rev() {
+            warn!(
+                "Skipping symlink {}: its target is not in the torrent",
+                files[i].path.display()
+            );
+            files.remove(i);
+        }
+    }
+}
+
+/// Reads a list of relative paths for `WalkedDir::from_list`, one per line
+/// or NUL-separated. `-` reads from stdin.
+pub fn read_file_list(path: &Path) -> Result<Vec<String>> {
+    let mut data = vec![];
+    if path == Path::new("-") {
+        io::stdin().read_to_end(&mut data)
+    } else {
+        fs::File::open(path).and_then(|mut f| f.read_to_end(&mut data))
+    }
+    .map_err(|e| Error::io(path, e))?;
+    let data = String::from_utf8(data).map_err(|_| {
+        Error::InvalidOption(format!("{} is not UTF-8", path.display()))
+    })?;
+    let entries: Vec<&str> = if data.contains('\0') {
+        data.split('\0').collect()
+    } else {
+        data.lines().collect()
+    };
+    Ok(entries
+        .into_iter()
+        .filter(|s| !s.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+// Sorts files according to the file tree in bt v2 spec.
+fn sort_files(files: &mut [DataFile]) {
+    files.sort_by_cached_key(|val| {
+        val.path_components
+            .iter()
+            .map(|s| s.as_bytes().to_vec())
+            .collect::<Vec<_>>()
+    });
+    info!("File list sorted.");
+}
+
+// Paths must be distinct and no file can be a folder of another one. Once
+// sorted, such paths are next to each other.
+fn check_paths(files: &[DataFile]) -> Result<()> {
+    for w in files.windows(2) {
+        let (a, b) = (&w[0].path_components, &w[1].path_components);
+        if b.starts_with(a) {
+            return Err(Error::InvalidInput(if a.len() == b.len() {
+                format!("Duplicate path: {}", a.join("/"))
+            } else {
+                format!("Both a file and a folder: {}", a.join("/"))
+            }));
+        }
+    }
+    Ok(())
+}
+
+/// Whether `name` can be used as the torrent name, i.e. a single path
+/// component.
+pub fn is_valid_name(name: &str) -> bool {
+    !(name.is_empty()
+        || name == "."
+        || name == ".."
+        || name.contains(&['/', '\\'][..]))
+}
+
+impl WalkOptions {
+    // Returns the (exclude, include) matchers rooted at `root`.
+    fn matchers(&self, root: &Path) -> Result<(Gitignore, Gitignore)> {
+        let mut exclude = GitignoreBuilder::new(root);
+        let torrentignore = root.join(TORRENTIGNORE);
+        if self.torrentignore && torrentignore.is_file() {
+            info!("Reading exclude patterns from {}", TORRENTIGNORE);
+            add_pattern(&mut exclude, &format!("/{}", TORRENTIGNORE))?;
+            add_pattern_file(&mut exclude, &torrentignore)?;
+        }
+        for path in &self.exclude_from {
+            add_pattern_file(&mut exclude, path)?;
+        }
+        for pattern in &self.exclude {
+            add_pattern(&mut exclude, pattern)?;
+        }
+
+        let mut include = GitignoreBuilder::new(root);
+        for pattern in &self.include {
+            add_pattern(&mut include, pattern)?;
+        }
+        Ok((build_matcher(exclude)?, build_matcher(include)?))
+    }
 }
 
 pub struct WalkedDir {
+    // Both empty for merged inputs
     pub canonical_path: PathBuf,
     pub prefix: PathBuf,
     pub files: Vec<DataFile>,
@@ -20,74 +288,531 @@ pub struct WalkedDir {
 impl WalkedDir {
     pub fn walk<P>(
         base: P,
+        options: &WalkOptions,
         progress: &mut ProgressIndicator,
-    ) -> io::Result<WalkedDir>
+    ) -> Result<WalkedDir>
     where
         P: AsRef<Path>,
     {
-        let canonical_path = base.as_ref().canonicalize().unwrap();
-        let prefix = canonical_path.parent().expect("Cannot be a root folder");
+        let canonical_path = base
+            .as_ref()
+            .canonicalize()
+            .map_err(|e| Error::io(&base, e))?;
+        let prefix = canonical_path.parent().ok_or(Error::RootDirectory)?;
+        if !options.include.is_empty() && !canonical_path.is_dir() {
+            return Err(Error::InvalidOption(format!(
+                "{} is not a folder, include patterns need one",
+                base.as_ref().display()
+            )));
+        }
+        let (exclude, include) = options.matchers(&canonical_path)?;
+        let mut excluded = 0usize;
+        let mut hidden = 0usize;
+        let mut skipped_links = 0usize;
         let mut files = vec![];
 
         // Walk folder tree
         progress.scan_begin();
         let dir_iter = WalkDir::new(canonical_path.clone())
-            .follow_links(true)
+            .follow_links(options.symlinks == SymlinkMode::Follow)
             .into_iter()
             .filter_entry(|entry| {
-                entry.depth() == 0
-                    || !entry
+                if entry.depth() == 0 {
+                    return true;
+                }
+                if options.symlinks == SymlinkMode::Skip
+                    && entry.path_is_symlink()
+                {
+                    debug!("Symlink: {}", entry.path().display());
+                    skipped_links += 1;
+                    return false;
+                }
+                if !options.include_hidden
+                    && entry
                         .file_name()
                         .to_str()
                         .map(|s| s.starts_with('.'))
                         .unwrap_or(false)
+                {
+                    debug!("Hidden: {}", entry.path().display());
+                    hidden += 1;
+                    return false;
+                }
+                let is_dir = entry.file_type().is_dir();
+                let keep = !exclude.matched(entry.path(), is_dir).is_ignore()
+                    && (is_dir
+                        || include.is_empty()
+                        || include
+                            .matched_path_or_any_parents(entry.path(), false)
+                            .is_ignore());
+                if !keep {
+                    debug!("Excluded: {}", entry.path().display());
+                    excluded += 1;
+                }
+                keep
             });
         for entry in dir_iter {
-            let entry = entry.unwrap();
-            if !entry.file_type().is_file() {
+            let entry = entry?;
+            let symlink_path = if entry.path_is_symlink()
+                && options.symlinks == SymlinkMode::Preserve
+            {
+                match symlink_target(entry.path(), &canonical_path)? {
+                    Some(t) => Some(t),
+                    None => {
+                        warn!(
+                            "Skipping symlink {}: dangling or pointing \
+                             outside of the input",
+                            entry.path().display()
+                        );
+                        continue;
+                    }
+                }
+            } else if entry.file_type().is_file() {
+                None
+            } else {
                 continue;
-            }
+            };
             let partial_path = entry.path().strip_prefix(prefix).unwrap();
             debug!("File: {}", partial_path.display());
-            progress.scan_progress(partial_path.to_str().unwrap());
+            let partial_str = partial_path.to_str().ok_or_else(|| {
+                Error::NonUtf8Path(entry.path().to_path_buf())
+            })?;
+            progress.scan_progress(partial_str);
 
-            // Split path components
-            let mut comp: Vec<String> = vec![];
-            for c in partial_path.components() {
+            files.push(DataFile {
+                metadata: entry.metadata()?,
+                path_components: path_components(partial_path, entry.path())?,
+                path: entry.into_path(),
+                symlink_path,
+            });
+        }
+        progress.scan_end();
+        if options.symlinks == SymlinkMode::Preserve {
+            drop_unlisted_targets(&mut files);
+        }
+        if hidden > 0 {
+            info!(
+                "Skipped {} hidden files and folders, \
+                 use --include-hidden to keep them",
+                hidden
+            );
+        }
+        if skipped_links > 0 {
+            info!("Skipped {} symlinks", skipped_links);
+        }
+        if excluded > 0 {
+            info!("Excluded {} files and folders by pattern", excluded);
+        }
+
+        sort_files(&mut files);
+        Ok(WalkedDir {
+            prefix: prefix.to_path_buf(),
+            canonical_path,
+            files,
+        })
+    }
+
+    /// Takes the given files of the folder `base` instead of walking it.
+    /// Paths are relative to `base` and symlinks are followed. Unlike `walk`,
+    /// no file is skipped.
+    pub fn from_list<P, S>(
+        base: P,
+        list: &[S],
+        progress: &mut ProgressIndicator,
+    ) -> Result<WalkedDir>
+    where
+        P: AsRef<Path>,
+        S: AsRef<str>,
+    {
+        let canonical_path = base
+            .as_ref()
+            .canonicalize()
+            .map_err(|e| Error::io(&base, e))?;
+        let prefix = canonical_path.parent().ok_or(Error::RootDirectory)?;
+        if !canonical_path.is_dir() {
+            return Err(Error::InvalidOption(format!(
+                "{} is not a folder, a file list needs one",
+                base.as_ref().display()
+            )));
+        }
+        let invalid = |msg: &str, entry: &str| {
+            Err(Error::InvalidOption(format!("{}: {}", msg, entry)))
+        };
+        let mut files = vec![];
+
+        progress.scan_begin();
+        for entry in list {
+            let entry = entry.as_ref();
+            let mut relative = PathBuf::new();
+            for c in Path::new(entry).components() {
                 match c {
-                    Component::Normal(os_str) => comp.push(
-                        os_str
-                            .to_str()
-                            .expect("Cannot encode path, this is not good")
-                            .to_string(),
-                    ),
+                    Component::Normal(c) => relative.push(c),
+                    Component::CurDir => (),
                     _ => {
-                        unreachable!("Invalid path: {}", partial_path.display())
+                        return invalid("Path escapes the input folder", entry)
                     }
                 }
             }
-
+            if relative.as_os_str().is_empty() {
+                return invalid("Not a file", entry);
+            }
+            let path = canonical_path.join(&relative);
+            // Symlinks on the way may still lead out of the input folder.
+            let real = path.canonicalize().map_err(|e| Error::io(&path, e))?;
+            if !real.starts_with(&canonical_path) {
+                return invalid("Path escapes the input folder", entry);
+            }
+            let metadata =
+                fs::metadata(&real).map_err(|e| Error::io(&path, e))?;
+            if !metadata.is_file() {
+                return invalid("Not a file", entry);
+            }
+            let partial_path = path.strip_prefix(prefix).unwrap();
+            debug!("File: {}", partial_path.display());
+            progress.scan_progress(entry);
             files.push(DataFile {
-                metadata: entry.metadata()?,
-                entry,
-                path_components: comp,
+                metadata,
+                path_components: path_components(partial_path, &path)?,
+                path,
+                symlink_path: None,
             });
         }
         progress.scan_end();
 
-        // Sort files according to the file tree in bt v2 spec.
-        files.sort_by_cached_key(|val| {
-            val.path_components
-                .iter()
-                .map(|s| s.as_bytes().to_vec())
-                .collect::<Vec<_>>()
-        });
-        info!("File list sorted.");
-
+        sort_files(&mut files);
+        check_paths(&files)?;
         Ok(WalkedDir {
             prefix: prefix.to_path_buf(),
             canonical_path,
             files,
         })
     }
+
+    /// Puts the files of several walked inputs under a virtual root folder
+    /// `name`, each input keeping its own name below it.
+    pub fn merge(name: &str, dirs: Vec<WalkedDir>) -> Result<WalkedDir> {
+        if !is_valid_name(name) {
+            return Err(Error::InvalidOption(format!(
+                "Invalid name: {}",
+                name
+            )));
+        }
+        // Each input keeps its own name, which must be unique.
+        let mut names = HashSet::new();
+        for dir in &dirs {
+            if let Some(f) = dir.files.first() {
+                let input = &f.path_components[0];
+                if !names.insert(input) {
+                    return Err(Error::InvalidInput(format!(
+                        "Several inputs are named {}",
+                        input
+                    )));
+                }
+            }
+        }
+        let mut files = vec![];
+        for dir in dirs {
+            for mut f in dir.files {
+                if let Some(target) = &mut f.symlink_path {
+                    target.insert(0, f.path_components[0].clone());
+                }
+                f.path_components.insert(0, name.to_string());
+                files.push(f);
+            }
+        }
+        sort_files(&mut files);
+        check_paths(&files)?;
+        Ok(WalkedDir {
+            canonical_path: PathBuf::new(),
+            prefix: PathBuf::new(),
+            files,
+        })
+    }
+}
+
+#[cfg(test)]
+mod walk_test {
+    use super::*;
+    use crate::test_util::{data_file, TempDir};
+
+    // Paths below the input folder
+    fn walk(root: &Path, options: &WalkOptions) -> Vec<String> {
+        let mut progress = ProgressIndicator::new(true);
+        WalkedDir::walk(root, options, &mut progress)
+            .unwrap()
+            .files
+            .iter()
+            .map(|f| f.path_components[1..].join("/"))
+            .collect()
+    }
+
+    fn tree(test: &str) -> TempDir {
+        let dir = TempDir::new(test);
+        for f in &["d/a.txt", "d/b.log", "d/sub/c.txt", "d/sub/d.log"] {
+            dir.write(f, b"x");
+        }
+        dir
+    }
+
+    #[test]
+    fn exclude() {
+        let dir = tree("walk-exclude");
+        let options = WalkOptions {
+            exclude: vec!["*.log".into()],
+            ..Default::default()
+        };
+        assert_eq!(
+            walk(&dir.path().join("d"), &options),
+            vec!["a.txt", "sub/c.txt"]
+        );
+        let options = WalkOptions {
+            exclude: vec!["/sub".into(), "b.*".into()],
+            ..Default::default()
+        };
+        assert_eq!(walk(&dir.path().join("d"), &options), vec!["a.txt"]);
+    }
+
+    #[test]
+    fn include() {
+        let dir = tree("walk-include");
+        let root = dir.path().join("d");
+        let options = WalkOptions {
+            include: vec!["*.log".into()],
+            ..Default::default()
+        };
+        assert_eq!(walk(&root, &options), vec!["b.log", "sub/d.log"]);
+        // A folder includes everything below it, minus the excluded files.
+        let options = WalkOptions {
+            include: vec!["sub/".into()],
+            exclude: vec!["*.log".into()],
+            ..Default::default()
+        };
+        assert_eq!(walk(&root, &options), vec!["sub/c.txt"]);
+
+        let mut progress = ProgressIndicator::new(true);
+        let options = WalkOptions {
+            include: vec!["*.txt".into()],
+            ..Default::default()
+        };
+        assert!(WalkedDir::walk(root.join("a.txt"), &options, &mut progress)
+            .is_err());
+    }
+
+    #[test]
+    fn torrentignore() {
+        let dir = tree("walk-torrentignore");
+        dir.write("d/.torrentignore", b"sub/\n*.txt\n");
+        let root = dir.path().join("d");
+        // The pattern file itself is left out, even with hidden files.
+        let options = WalkOptions {
+            include_hidden: true,
+            ..Default::default()
+        };
+        assert_eq!(walk(&root, &options), vec!["b.log"]);
+        let options = WalkOptions {
+            torrentignore: false,
+            ..Default::default()
+        };
+        assert_eq!(
+            walk(&root, &options),
+            vec!["a.txt", "b.log", "sub/c.txt", "sub/d.log"]
+        );
+    }
+
+    #[test]
+    fn hidden() {
+        let dir = TempDir::new("walk-hidden");
+        for f in &["d/a", "d/.h", "d/.hdir/b", "d/sub/.c"] {
+            dir.write(f, b"x");
+        }
+        let root = dir.path().join("d");
+        assert_eq!(walk(&root, &WalkOptions::default()), vec!["a"]);
+        let options = WalkOptions {
+            include_hidden: true,
+            ..Default::default()
+        };
+        assert_eq!(walk(&root, &options), vec![".h", ".hdir/b", "a", "sub/.c"]);
+    }
+
+    #[cfg(unix)]
+    fn symlink_tree(test: &str) -> TempDir {
+        use std::os::unix::fs::symlink;
+        let dir = TempDir::new(test);
+        dir.write("d/a", b"x");
+        dir.write("d/sub/b", b"x");
+        dir.write("d/.h", b"x");
+        let outside = dir.write("outside", b"x");
+        let root = dir.path().join("d");
+        symlink("a", root.join("l1")).unwrap();
+        symlink("l1", root.join("l2")).unwrap();
+        symlink("sub", root.join("ls")).unwrap();
+        symlink("../a", root.join("sub/up")).unwrap();
+        symlink(".h", root.join("lh")).unwrap();
+        symlink(outside, root.join("out")).unwrap();
+        dir
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks() {
+        let dir = symlink_tree("walk-symlinks");
+        let root = dir.path().join("d");
+        let walk_mode = |symlinks| {
+            let options = WalkOptions {
+                symlinks,
+                ..Default::default()
+            };
+            let mut progress = ProgressIndicator::new(true);
+            WalkedDir::walk(&root, &options, &mut progress)
+                .unwrap()
+                .files
+                .iter()
+                .map(|f| {
+                    let target = f.symlink_path.as_ref().map(|t| t.join("/"));
+                    (f.path_components[1..].join("/"), target)
+                })
+                .collect::<Vec<_>>()
+        };
+        let file = |p: &str| (p.to_string(), None);
+        let link = |p: &str, t: &str| (p.to_string(), Some(t.to_string()));
+
+        assert_eq!(
+            walk_mode(SymlinkMode::Follow),
+            vec![
+                file("a"),
+                file("l1"),
+                file("l2"),
+                file("lh"),
+                file("ls/b"),
+                file("ls/up"),
+                file("out"),
+                file("sub/b"),
+                file("sub/up"),
+            ]
+        );
+        // Added after the follow walk, which fails on dangling links.
+        std::os::unix::fs::symlink("missing", root.join("dangling")).unwrap();
+        assert_eq!(
+            walk_mode(SymlinkMode::Skip),
+            vec![file("a"), file("sub/b")]
+        );
+        // Each link keeps its own target. Links leaving the input, dangling
+        // or pointing to a hidden file are dropped.
+        assert_eq!(
+            walk_mode(SymlinkMode::Preserve),
+            vec![
+                file("a"),
+                link("l1", "a"),
+                link("l2", "l1"),
+                link("ls", "sub"),
+                file("sub/b"),
+                link("sub/up", "a"),
+            ]
+        );
+    }
+
+    #[test]
+    fn file_list() {
+        let dir = tree("walk-file-list");
+        let lines = dir.write("lines", b"a.txt\n./sub/c.txt\n\n");
+        let nul = dir.write("nul", b"a.txt\0./sub/c.txt\0");
+        let list = read_file_list(&lines).unwrap();
+        assert_eq!(list, vec!["a.txt", "./sub/c.txt"]);
+        assert_eq!(read_file_list(&nul).unwrap(), list);
+
+        let root = dir.path().join("d");
+        let mut progress = ProgressIndicator::new(true);
+        let mut from_list = |list: &[&str]| {
+            WalkedDir::from_list(&root, list, &mut progress).map(|d| {
+                d.files
+                    .iter()
+                    .map(|f| f.path_components.join("/"))
+                    .collect::<Vec<_>>()
+            })
+        };
+        assert_eq!(
+            from_list(&["sub/c.txt", "b.log"]).unwrap(),
+            vec!["d/b.log", "d/sub/c.txt"]
+        );
+        assert!(from_list(&["../lines"]).is_err());
+        assert!(from_list(&["sub/../a.txt"]).is_err());
+        assert!(from_list(&["sub"]).is_err());
+        assert!(from_list(&["a.txt", "./a.txt"]).is_err());
+        assert!(matches!(from_list(&["missing"]), Err(Error::Io { .. })));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn file_list_symlink_escape() {
+        let dir = tree("walk-file-list-symlink");
+        dir.write("outside/secret", b"x");
+        let root = dir.path().join("d");
+        std::os::unix::fs::symlink("../outside", root.join("out")).unwrap();
+        std::os::unix::fs::symlink("sub", root.join("in")).unwrap();
+        let mut progress = ProgressIndicator::new(true);
+        assert!(WalkedDir::from_list(&root, &["out/secret"], &mut progress)
+            .is_err());
+        // Links staying inside are followed, under their own name.
+        let walked =
+            WalkedDir::from_list(&root, &["in/c.txt"], &mut progress).unwrap();
+        assert_eq!(walked.files[0].path_components, vec!["d", "in", "c.txt"]);
+    }
+
+    #[test]
+    fn merge() {
+        let dir = tree("walk-merge");
+        let file = dir.write("e", b"x");
+        dir.write("other/d/f", b"x");
+        dir.write("x/e/g", b"x");
+        let mut progress = ProgressIndicator::new(true);
+        let mut walk = |p: &Path| {
+            WalkedDir::walk(p, &WalkOptions::default(), &mut progress).unwrap()
+        };
+        let d = walk(&dir.path().join("d"));
+        let merged = WalkedDir::merge("r", vec![walk(&file), d]).unwrap();
+        let paths: Vec<_> = merged
+            .files
+            .iter()
+            .map(|f| f.path_components.join("/"))
+            .collect();
+        assert_eq!(
+            paths,
+            vec![
+                "r/d/a.txt",
+                "r/d/b.log",
+                "r/d/sub/c.txt",
+                "r/d/sub/d.log",
+                "r/e"
+            ]
+        );
+
+        // Same name in different folders
+        let d = walk(&dir.path().join("d"));
+        let other = walk(&dir.path().join("other/d"));
+        assert!(matches!(
+            WalkedDir::merge("r", vec![d, other]),
+            Err(Error::InvalidInput(_))
+        ));
+        // A file and a folder of the same name
+        let folder = walk(&dir.path().join("x/e"));
+        assert!(matches!(
+            WalkedDir::merge("r", vec![walk(&file), folder]),
+            Err(Error::InvalidInput(_))
+        ));
+        assert!(WalkedDir::merge("a/b", vec![walk(&file)]).is_err());
+    }
+
+    #[test]
+    fn file_and_folder() {
+        let file = |p: &[&str]| data_file(".", p);
+        let mut files = vec![
+            file(&["r", "a", "b"]),
+            file(&["r", "a"]),
+            file(&["r", "a.txt"]),
+        ];
+        sort_files(&mut files);
+        assert!(matches!(check_paths(&files), Err(Error::InvalidInput(_))));
+        files.remove(0);
+        assert!(check_paths(&files).is_ok());
+    }
 }
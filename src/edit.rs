@@ -0,0 +1,232 @@
+use crate::bencode::BencodeValue;
+use crate::dirwalker::is_valid_name;
+use crate::error::{Error, Result};
+use crate::metainfo::MetaInfo;
+use crate::torrent_meta::insert_trackers;
+
+use std::collections::BTreeMap;
+
+/// Changes to the fields of a torrent outside of the info dict. These
+/// don't affect the info hash, so no re-hashing is needed.
+///
+/// Replacements apply first, then additions, then removals.
+#[derive(Debug, Default)]
+pub struct Edit {
+    /// Replaces all tracker tiers. BEP12
+    pub announces: Option<Vec<Vec<String>>>,
+    pub add_announces: Vec<Vec<String>>,
+    /// Tracker URLs to remove from every tier. Empty tiers are dropped.
+    pub remove_announces: Vec<String>,
+    /// Replaces all DHT nodes. BEP5
+    pub nodes: Option<Vec<(String, u16)>>,
+    pub add_nodes: Vec<(String, u16)>,
+    pub remove_nodes: Vec<(String, u16)>,
+    /// Replaces all WebSeed URLs. BEP19
+    pub webseeds: Option<Vec<String>>,
+    pub add_webseeds: Vec<String>,
+    pub remove_webseeds: Vec<String>,
+    /// Some(None) removes the comment.
+    pub comment: Option<Option<String>>,
+    /// Some(None) removes "created by".
+    pub created_by: Option<Option<String>>,
+}
+
+fn set_or_remove(
+    root: &mut BTreeMap<Vec<u8>, BencodeValue>,
+    key: &[u8],
+    value: &Option<Option<String>>,
+) {
+    match value {
+        None => (),
+        Some(None) => {
+            root.remove(key);
+        }
+        Some(Some(s)) => {
+            root.insert(key.to_vec(), BencodeValue::from(s.as_str()));
+        }
+    }
+}
+
+impl Edit {
+    /// Applies the changes to the root dict of `torrent`. The info dict is
+    /// left untouched.
+    pub fn apply(&self, torrent: &mut BencodeValue) -> Result<()> {
+        let meta = MetaInfo::from_bencode(torrent)?;
+        let root = match torrent {
+            BencodeValue::Map(m) => m,
+            _ => unreachable!("checked by MetaInfo"),
+        };
+
+        let mut announces = match &self.announces {
+            Some(a) => a.clone(),
+            None => meta.announces,
+        };
+        announces.extend(self.add_announces.iter().cloned());
+        for tier in &mut announces {
+            tier.retain(|url| !self.remove_announces.contains(url));
+        }
+        announces.retain(|tier| !tier.is_empty());
+
+        let mut nodes = match &self.nodes {
+            Some(n) => n.clone(),
+            None => meta.nodes,
+        };
+        nodes.extend(self.add_nodes.iter().cloned());
+        nodes.retain(|n| !self.remove_nodes.contains(n));
+
+        let mut webseeds = match &self.webseeds {
+            Some(w) => w.clone(),
+            None => meta.webseeds,
+        };
+        webseeds.extend(self.add_webseeds.iter().cloned());
+        webseeds.retain(|w| !self.remove_webseeds.contains(w));
+
+        for key in &[&b"announce"[..], b"announce-list", b"nodes", b"url-list"]
+        {
+            root.remove(*key);
+        }
+        insert_trackers(root, &announces, &nodes, &webseeds);
+        set_or_remove(root, b"comment", &self.comment);
+        set_or_remove(root, b"created by", &self.created_by);
+        Ok(())
+    }
+}
+
+/// Changes to the info dict that keep the piece hashes valid. `pieces`,
+/// `file tree` and `piece layers` are reused as they are, but the info hash
+/// changes.
+#[derive(Debug, Default)]
+pub struct Retarget {
+    /// Sets or clears the private flag. BEP27
+    pub private: Option<bool>,
+    /// Some(None) removes the source tag.
+    pub source: Option<Option<String>>,
+    /// New name of the torrent, i.e. the root folder or the single file.
+    pub name: Option<String>,
+}
+
+impl Retarget {
+    /// Applies the changes to the info dict of `torrent`.
+    pub fn apply(&self, torrent: &mut BencodeValue) -> Result<()> {
+        let old = MetaInfo::from_bencode(torrent)?;
+        // Checked first, so that an error leaves the torrent as it was.
+        if let Some(name) = &self.name {
+            if !is_valid_name(name) {
+                return Err(Error::InvalidOption(format!(
+                    "Invalid name: {}",
+                    name
+                )));
+            }
+        }
+        let info = match torrent {
+            BencodeValue::Map(m) => match m.get_mut(&b"info"[..]) {
+                Some(BencodeValue::Map(info)) => info,
+                _ => unreachable!("checked by MetaInfo"),
+            },
+            _ => unreachable!("checked by MetaInfo"),
+        };
+
+        match self.private {
+            Some(true) => {
+                info.insert(b"private".to_vec(), BencodeValue::from(1));
+            }
+            Some(false) => {
+                info.remove(&b"private"[..]);
+            }
+            None => (),
+        }
+        set_or_remove(info, b"source", &self.source);
+
+        if let Some(name) = &self.name {
+            // BEP52 single file mode: the file tree holds the file under
+            // the torrent name.
+            let single_file =
+                old.files.len() == 1 && old.files[0].path.is_empty();
+            if let Some(BencodeValue::Map(tree)) =
+                info.get_mut(&b"file tree"[..])
+            {
+                if single_file {
+                    if let Some(f) = tree.remove(old.name.as_bytes()) {
+                        tree.insert(name.as_bytes().to_vec(), f);
+                    }
+                }
+            }
+            info.insert(b"name".to_vec(), BencodeValue::from(name.as_str()));
+        }
+
+        MetaInfo::from_bencode(torrent)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod edit_test {
+    use super::*;
+
+    #[test]
+    fn info_untouched() {
+        let data = b"d8:announce5:http:7:comment3:old\
+                     4:infod6:lengthi1e4:name1:a12:piece lengthi16384e\
+                     6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+        let mut root = BencodeValue::deserialize(data).unwrap();
+        let info = root.get(b"info").unwrap().serialize();
+        Edit {
+            add_announces: vec![vec!["udp:".into()]],
+            remove_announces: vec!["http:".into()],
+            webseeds: Some(vec!["http://w/".into()]),
+            comment: Some(None),
+            ..Default::default()
+        }
+        .apply(&mut root)
+        .unwrap();
+        assert_eq!(info, root.get(b"info").unwrap().serialize());
+        assert_eq!(
+            root.serialize(),
+            b"d8:announce4:udp:\
+              4:infod6:lengthi1e4:name1:a12:piece lengthi16384e\
+              6:pieces20:aaaaaaaaaaaaaaaaaaaae8:url-list9:http://w/e"
+                .to_vec()
+        );
+    }
+
+    #[test]
+    fn retarget_single_file() {
+        let data = b"d4:infod9:file treed1:ad0:d6:lengthi1e\
+                     11:pieces root32:bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbeee\
+                     6:lengthi1e12:meta versioni2e4:name1:a\
+                     12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaa\
+                     7:privatei1eee";
+        let mut root = BencodeValue::deserialize(data).unwrap();
+        Retarget {
+            private: Some(false),
+            source: Some(Some("X".into())),
+            name: Some("b".into()),
+        }
+        .apply(&mut root)
+        .unwrap();
+        assert_eq!(
+            root.serialize(),
+            b"d4:infod9:file treed1:bd0:d6:lengthi1e\
+              11:pieces root32:bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbeee\
+              6:lengthi1e12:meta versioni2e4:name1:b\
+              12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaa\
+              6:source1:Xee"
+                .to_vec()
+        );
+    }
+
+    #[test]
+    fn retarget_invalid_name() {
+        let data = b"d4:infod6:lengthi1e4:name1:a12:piece lengthi16384e\
+                     6:pieces20:aaaaaaaaaaaaaaaaaaaa7:privatei1eee";
+        let mut root = BencodeValue::deserialize(data).unwrap();
+        let ret = Retarget {
+            private: Some(false),
+            source: Some(Some("X".into())),
+            name: Some("../b".into()),
+        }
+        .apply(&mut root);
+        assert!(ret.is_err());
+        assert_eq!(root.serialize(), data.to_vec());
+    }
+}
@@ -0,0 +1,93 @@
+use crate::bencode::DecodeError;
+
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug)]
+pub enum Error {
+    /// An I/O operation on `path` failed.
+    Io { path: PathBuf, source: io::Error },
+    /// Piece size is not a power of 2 or out of the supported range.
+    InvalidPieceSize(u64),
+    /// No file to put in the torrent.
+    EmptyInput,
+    /// A file name can't be represented in the torrent.
+    NonUtf8Path(PathBuf),
+    /// The input is a file system root, which has no name.
+    RootDirectory,
+    /// The input files can't be laid out in a torrent, e.g. two of them
+    /// have the same path.
+    InvalidInput(String),
+    /// A combination of options that can't be satisfied.
+    InvalidOption(String),
+    /// The torrent file is not valid bencode.
+    Decode(DecodeError),
+    /// The torrent file is valid bencode, but not a valid torrent.
+    InvalidTorrent(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub fn io<P: AsRef<Path>>(path: P, source: io::Error) -> Self {
+        Error::Io {
+            path: path.as_ref().to_path_buf(),
+            source,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io { path, source } => {
+                write!(f, "{}: {}", path.display(), source)
+            }
+            Error::InvalidPieceSize(x) => write!(
+                f,
+                "Invalid piece size {}: must be a power of 2 between \
+                 16KiB and 64MiB",
+                x
+            ),
+            Error::EmptyInput => write!(f, "No file selected"),
+            Error::NonUtf8Path(p) => {
+                write!(f, "Cannot encode path as UTF-8: {}", p.display())
+            }
+            Error::RootDirectory => write!(f, "Cannot be a root folder"),
+            Error::InvalidInput(s) => write!(f, "{}", s),
+            Error::InvalidOption(s) => write!(f, "{}", s),
+            Error::Decode(e) => write!(f, "{}", e),
+            Error::InvalidTorrent(s) => write!(f, "Invalid torrent: {}", s),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            Error::Decode(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<DecodeError> for Error {
+    fn from(e: DecodeError) -> Self {
+        Error::Decode(e)
+    }
+}
+
+impl From<walkdir::Error> for Error {
+    fn from(e: walkdir::Error) -> Self {
+        let path = e.path().map(Path::to_path_buf).unwrap_or_default();
+        let msg = e.to_string();
+        let source = match e.into_io_error() {
+            Some(source) => source,
+            // File system loop
+            None => io::Error::new(io::ErrorKind::InvalidInput, msg),
+        };
+        Error::Io { path, source }
+    }
+}
@@ -0,0 +1,116 @@
+use crate::bencode::BencodeValue;
+
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Info hashes of a torrent, computed over the bencoded `info` dict.
+pub struct InfoHashes {
+    // BEP3 SHA-1, present if the torrent has v1 metadata
+    pub v1: Option<Vec<u8>>,
+    // BEP52 SHA-256, present if the torrent has v2 metadata
+    pub v2: Option<Vec<u8>>,
+}
+
+impl InfoHashes {
+    pub fn from_torrent(root: &BencodeValue) -> Option<InfoHashes> {
+        let info = root.get(b"info")?;
+        let encoded = info.serialize();
+        let v1 = info.get(b"pieces").map(|_| Sha1::digest(&encoded).to_vec());
+        let v2 = info
+            .get(b"meta version")
+            .filter(|v| v.as_integer() == Some(2))
+            .map(|_| Sha256::digest(&encoded).to_vec());
+        Some(InfoHashes { v1, v2 })
+    }
+
+    /// The v2 hash truncated to 20 bytes, as used by the wire protocol and
+    /// trackers.
+    pub fn v2_truncated(&self) -> Option<&[u8]> {
+        self.v2.as_ref().map(|h| &h[..20])
+    }
+
+    pub fn to_json(&self) -> String {
+        let field = |h: Option<&[u8]>| match h {
+            Some(h) => format!("\"{}\"", to_hex(h)),
+            None => "null".to_string(),
+        };
+        format!(
+            "{{\"info_hash_v1\": {}, \"info_hash_v2\": {}, \
+             \"info_hash_v2_truncated\": {}}}",
+            field(self.v1.as_deref()),
+            field(self.v2.as_deref()),
+            field(self.v2_truncated()),
+        )
+    }
+}
+
+impl std::fmt::Display for InfoHashes {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(h) = &self.v1 {
+            writeln!(f, "Info hash v1: {}", to_hex(h))?;
+        }
+        if let Some(h) = &self.v2 {
+            writeln!(f, "Info hash v2: {}", to_hex(h))?;
+            writeln!(f, "Info hash v2 (truncated): {}", to_hex(&h[..20]))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{to_hex, InfoHashes};
+    use crate::bencode::BencodeValue;
+
+    fn torrent(info: &[u8]) -> BencodeValue {
+        let mut data = b"d4:info".to_vec();
+        data.extend_from_slice(info);
+        data.push(b'e');
+        BencodeValue::deserialize(&data).unwrap()
+    }
+
+    #[test]
+    fn v1() {
+        let mut info =
+            b"d6:lengthi9e4:name1:a12:piece lengthi16384e6:pieces20:".to_vec();
+        info.extend_from_slice(&[0; 20]);
+        info.push(b'e');
+        let hashes = InfoHashes::from_torrent(&torrent(&info)).unwrap();
+        assert_eq!(
+            to_hex(hashes.v1.as_ref().unwrap()),
+            "b67e4f3f8e87b667ff9a9660627b55ff95916578"
+        );
+        assert!(hashes.v2.is_none());
+    }
+
+    #[test]
+    fn v2() {
+        let mut info =
+            b"d9:file treed1:ad0:d6:lengthi9e11:pieces root32:".to_vec();
+        info.extend_from_slice(&[0x11; 32]);
+        info.extend_from_slice(
+            b"eee12:meta versioni2e4:name1:a12:piece lengthi16384ee",
+        );
+        let hashes = InfoHashes::from_torrent(&torrent(&info)).unwrap();
+        assert!(hashes.v1.is_none());
+        assert_eq!(
+            to_hex(hashes.v2.as_ref().unwrap()),
+            "c94570a8cbc3ab31664d2671096c52e10761333637c0ee9e45424325b8b8e895"
+        );
+        assert_eq!(
+            to_hex(hashes.v2_truncated().unwrap()),
+            "c94570a8cbc3ab31664d2671096c52e107613336"
+        );
+        assert_eq!(
+            hashes.to_json(),
+            "{\"info_hash_v1\": null, \"info_hash_v2\": \"c94570a8cbc3ab31664d\
+             2671096c52e10761333637c0ee9e45424325b8b8e895\", \
+             \"info_hash_v2_truncated\": \
+             \"c94570a8cbc3ab31664d2671096c52e107613336\"}"
+        );
+    }
+}
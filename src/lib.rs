@@ -0,0 +1,52 @@
+// mktorrent-rs creates the BitTorrent metadata file for file sharing.
+// Copyright (C) 2021  Recursive G
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Library behind the mktorrent-rs command line tool.
+//!
+//! ```no_run
+//! use mktorrent::TorrentBuilder;
+//!
+//! let torrent = TorrentBuilder::new("/path/to/data")
+//!     .announce("http://tracker.example.com/announce")
+//!     .threads(4)
+//!     .build()
+//!     .unwrap();
+//! std::fs::write("data.torrent", torrent.to_bytes()).unwrap();
+//! println!("{}", torrent.magnet_uri());
+//! ```
+
+pub mod bencode;
+mod builder;
+pub mod dirwalker;
+pub mod edit;
+pub mod error;
+pub mod infohash;
+pub mod magnet;
+pub mod metainfo;
+pub mod piece_size;
+pub mod progress;
+pub mod reader;
+pub mod show;
+#[cfg(test)]
+mod test_util;
+mod torrent_meta;
+mod torrent_meta_v2;
+pub mod verify;
+
+pub use builder::{Torrent, TorrentBuilder, CREATED_BY};
+pub use error::{Error, Result};
+pub use reader::IoMode;
+pub use torrent_meta::PadStyle;
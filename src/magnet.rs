@@ -0,0 +1,126 @@
+use crate::bencode::BencodeValue;
+use crate::infohash::{to_hex, InfoHashes};
+
+fn percent_encode(s: &[u8]) -> String {
+    let mut ret = String::new();
+    for &b in s {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+            ret.push(b as char);
+        } else {
+            ret.push_str(&format!("%{:02X}", b));
+        }
+    }
+    ret
+}
+
+// Trackers of all tiers in order, falling back to "announce" if there is
+// no BEP12 list.
+fn trackers(torrent: &BencodeValue) -> Vec<&[u8]> {
+    let mut ret: Vec<&[u8]> = vec![];
+    if let Some(tiers) = torrent.get(b"announce-list").and_then(|l| l.as_list())
+    {
+        for tier in tiers.iter().filter_map(BencodeValue::as_list) {
+            for url in tier.iter().filter_map(BencodeValue::as_bytes) {
+                if !ret.contains(&url) {
+                    ret.push(url);
+                }
+            }
+        }
+    } else if let Some(url) =
+        torrent.get(b"announce").and_then(|a| a.as_bytes())
+    {
+        ret.push(url);
+    }
+    ret
+}
+
+fn webseeds(torrent: &BencodeValue) -> Vec<&[u8]> {
+    match torrent.get(b"url-list") {
+        Some(BencodeValue::Bytes(b)) => vec![b],
+        Some(BencodeValue::List(l)) => {
+            l.iter().filter_map(BencodeValue::as_bytes).collect()
+        }
+        _ => vec![],
+    }
+}
+
+/// Builds a BEP9 magnet link for the torrent. Hybrid torrents get both the
+/// `btih` and the BEP52 `btmh` exact topic.
+pub fn magnet_uri(torrent: &BencodeValue) -> Option<String> {
+    let hashes = InfoHashes::from_torrent(torrent)?;
+    let mut params = vec![];
+    if let Some(h) = &hashes.v1 {
+        params.push(format!("xt=urn:btih:{}", to_hex(h)));
+    }
+    if let Some(h) = &hashes.v2 {
+        // multihash: 0x12 = sha2-256, 0x20 = 32 bytes
+        params.push(format!("xt=urn:btmh:1220{}", to_hex(h)));
+    }
+    if let Some(name) = torrent
+        .get(b"info")
+        .and_then(|i| i.get(b"name"))
+        .and_then(|n| n.as_bytes())
+    {
+        params.push(format!("dn={}", percent_encode(name)));
+    }
+    for url in trackers(torrent) {
+        params.push(format!("tr={}", percent_encode(url)));
+    }
+    for url in webseeds(torrent) {
+        params.push(format!("ws={}", percent_encode(url)));
+    }
+    Some(format!("magnet:?{}", params.join("&")))
+}
+
+#[cfg(test)]
+mod test {
+    use super::magnet_uri;
+    use crate::bencode::BencodeValue;
+    use crate::infohash::to_hex;
+    use sha1::{Digest, Sha1};
+
+    #[test]
+    fn v1() {
+        let mut data = b"d8:announce13:http://t/a?b=\
+                         13:announce-listll13:http://t/a?b=\
+                         10:udp://t:80el13:http://t/a?b=ee\
+                         4:infod6:lengthi9e4:name5:a b&c\
+                         12:piece lengthi16384e6:pieces20:"
+            .to_vec();
+        data.extend_from_slice(&[0; 20]);
+        data.extend_from_slice(b"e8:url-list10:http://w/xe");
+        let torrent = BencodeValue::deserialize(&data).unwrap();
+        let info = torrent.get(b"info").unwrap().serialize();
+        let ih = to_hex(&Sha1::digest(&info));
+        assert_eq!(
+            magnet_uri(&torrent).unwrap(),
+            format!(
+                "magnet:?xt=urn:btih:{}&dn=a%20b%26c\
+                 &tr=http%3A%2F%2Ft%2Fa%3Fb%3D&tr=udp%3A%2F%2Ft%3A80\
+                 &ws=http%3A%2F%2Fw%2Fx",
+                ih
+            )
+        );
+    }
+
+    #[test]
+    fn hybrid() {
+        let mut data = b"d4:infod9:file treed1:ad0:d6:lengthi9e\
+                         11:pieces root32:"
+            .to_vec();
+        data.extend_from_slice(&[0x11; 32]);
+        data.extend_from_slice(
+            b"eee6:lengthi9e12:meta versioni2e4:name1:a\
+              12:piece lengthi16384e6:pieces20:",
+        );
+        data.extend_from_slice(&[0; 20]);
+        data.extend_from_slice(b"ee");
+        let torrent = BencodeValue::deserialize(&data).unwrap();
+        let uri = magnet_uri(&torrent).unwrap();
+        assert!(uri.starts_with("magnet:?xt=urn:btih:"));
+        assert!(uri.contains("&xt=urn:btmh:1220"));
+        assert!(uri.ends_with("&dn=a"));
+        let prefix_len = "magnet:?xt=urn:btih:&xt=urn:btmh:1220&dn=a".len();
+        assert_eq!(uri.len(), prefix_len + 40 + 64);
+    }
+}
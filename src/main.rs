@@ -14,21 +14,56 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-mod bencode;
-mod dirwalker;
-mod progress;
-mod torrent_meta;
-mod torrent_meta_v2;
-
-use dirwalker::WalkedDir;
-use progress::ProgressIndicator;
-use torrent_meta::TorrentMetadata as TorrentMetadataV1;
-use torrent_meta_v2::TorrentMetadata as TorrentMetadataV2;
-
-use clap::Clap;
+use mktorrent::bencode::BencodeValue;
+use mktorrent::dirwalker::SymlinkMode;
+use mktorrent::edit::{Edit, Retarget};
+use mktorrent::infohash::InfoHashes;
+use mktorrent::metainfo::MetaInfo;
+use mktorrent::piece_size::PieceSizePolicy;
+use mktorrent::progress::ProgressIndicator;
+use mktorrent::show;
+use mktorrent::verify::{self, FileStatus};
+use mktorrent::{Error, IoMode, PadStyle, TorrentBuilder};
+
+use clap::{Clap, IntoApp};
 use log::*;
+use std::ffi::OsString;
 use std::fs::OpenOptions;
 use std::io::Write;
+use std::path::Path;
+use std::process::exit;
+
+// Exit status 1 is reserved for verification failures.
+fn exit_code(e: &Error) -> i32 {
+    match e {
+        Error::InvalidOption(_) | Error::InvalidPieceSize(_) => 2,
+        Error::Io { .. } => 3,
+        Error::EmptyInput
+        | Error::NonUtf8Path(_)
+        | Error::RootDirectory
+        | Error::InvalidInput(_) => 4,
+        Error::Decode(_) | Error::InvalidTorrent(_) => 5,
+    }
+}
+
+fn fail(e: Error) -> ! {
+    error!("{}", e);
+    exit(exit_code(&e));
+}
+
+// "key=value" for a string, "key=int:value" for an integer.
+fn parse_custom_key(s: &str) -> Result<(String, BencodeValue), Error> {
+    let invalid = || Error::InvalidOption(format!("Invalid key=value: {}", s));
+    let (key, value) = s.split_once('=').ok_or_else(invalid)?;
+    if key.is_empty() {
+        return Err(invalid());
+    }
+    let value = match value.strip_prefix("int:") {
+        Some(i) => BencodeValue::Integer(i.parse().map_err(|_| invalid())?),
+        None => BencodeValue::from(value),
+    };
+    Ok((key.to_string(), value))
+}
 
 fn split_host_port(s: &str) -> Option<(String, u16)> {
     let (l, r) = s.rsplit_once(':')?;
@@ -44,28 +79,119 @@ fn split_host_port(s: &str) -> Option<(String, u16)> {
     Some((host, port))
 }
 
-/// Creates BitTorrent metadata file.
+/// Creates and checks BitTorrent metadata files.
 #[derive(Clap, Debug)]
 #[clap(name = "mktorrent-rs", version = "0.1.0", author = "Recursive G")]
 struct CliOptions {
-    /// Input file or folder.
-    input: String,
+    /// A level of verbosity, and can be used multiple times.
+    #[clap(short, long, parse(from_occurrences), global = true)]
+    verbose: i32,
+
+    #[clap(subcommand)]
+    command: Command,
+}
+
+// Parsed once, size doesn't matter.
+#[allow(clippy::large_enum_variant)]
+#[derive(Clap, Debug)]
+enum Command {
+    /// Creates BitTorrent metadata file. The default when no command is
+    /// given.
+    Create(CreateOptions),
+    /// Checks on-disk data against an existing torrent file.
+    Verify(VerifyOptions),
+    /// Prints the content of an existing torrent file.
+    Show(ShowOptions),
+    /// Changes trackers, nodes, WebSeeds, comment or created by of an
+    /// existing torrent file. The info hash stays the same.
+    Edit(EditOptions),
+    /// Changes private flag, source or name of an existing torrent file
+    /// without re-hashing the data. The info hash changes.
+    Retarget(RetargetOptions),
+}
+
+#[derive(Clap, Debug)]
+struct CreateOptions {
+    /// Input files or folders. Several inputs need --name.
+    #[clap(required = true)]
+    input: Vec<String>,
+    /// Put the inputs under a virtual root folder with this name, which
+    /// becomes the torrent name.
+    #[clap(long)]
+    name: Option<String>,
     /// Output torrent file.
     #[clap(short, long)]
     output: String,
 
-    /// Bytes of each piece. Must be a power of 2. 16KB minimal. Leave unset for auto.
+    /// Take the files listed in this file instead of walking the input
+    /// folder. Paths are relative to the input, one per line or
+    /// NUL-separated. Use - for stdin.
+    #[clap(long, conflicts_with_all = &["exclude", "exclude-from", "include"])]
+    files_from: Option<String>,
+    /// Skip files and folders matching this gitignore-style pattern.
+    /// Use this option multiple times to specify multiple patterns.
+    #[clap(long)]
+    exclude: Vec<String>,
+    /// Read exclude patterns from a file, one per line.
+    #[clap(long)]
+    exclude_from: Vec<String>,
+    /// Only keep files matching this gitignore-style pattern. Needs a folder
+    /// input. Use this option multiple times to specify multiple patterns.
+    #[clap(long)]
+    include: Vec<String>,
+    /// Do not read exclude patterns from .torrentignore in the input folder.
+    #[clap(long)]
+    no_torrentignore: bool,
+    /// Include files and folders whose name starts with a dot.
+    #[clap(long)]
+    include_hidden: bool,
+    /// What to do with symlinks: follow them, skip them, or preserve them
+    /// as BEP-47 symlink entries (links pointing inside the input only).
+    #[clap(long, default_value = "follow",
+           possible_values = &["follow", "skip", "preserve"])]
+    symlinks: SymlinkMode,
+
+    /// Bytes of each piece. Must be a power of 2 between 16KiB and 64MiB.
+    /// Leave unset for auto.
     #[clap(short, long)]
     piece_size: Option<u64>,
+    /// Auto piece size: pick the smallest piece size giving at most this
+    /// many pieces. Default 2048.
+    #[clap(long, conflicts_with_all = &["piece-size", "max-torrent-size"])]
+    target_pieces: Option<u64>,
+    /// Auto piece size: pick the smallest piece size keeping the .torrent
+    /// file under this many bytes.
+    #[clap(long, conflicts_with = "piece-size")]
+    max_torrent_size: Option<u64>,
     /// Do not generate BEP-3 (BitTorrent v1) metadata.
     #[clap(long)]
     no_bep3: bool,
     /// Do not generate BEP-52 (BitTorrent v2) metadata.
     #[clap(long)]
     no_bep52: bool,
-    /// Do not generate BEP-47 padding files.
+    /// Do not generate BEP-47 padding files. Needs --no-bep52.
     #[clap(long)]
     no_padding: bool,
+    /// Also pad the last file, so that the torrent ends with a padding
+    /// file.
+    #[clap(long, conflicts_with = "no-padding")]
+    pad_last: bool,
+    /// Only align files larger than this many bytes to pieces. Smaller
+    /// files share pieces. Needs --no-bep52.
+    #[clap(long, conflicts_with = "no-padding")]
+    pad_min_size: Option<u64>,
+    /// Name padding files .pad/<N> (bep47) or _____padding_file_<i>_
+    /// (legacy, for BitComet and libtorrent 1.x).
+    #[clap(long, default_value = "bep47",
+           possible_values = &["bep47", "legacy"])]
+    pad_style: PadStyle,
+    /// Mark executable and hidden files with BEP-47 attributes.
+    #[clap(long)]
+    preserve_attrs: bool,
+    /// Store the SHA-1 of each file (BEP-47). Needs BEP-3 metadata. With
+    /// several threads, parts of large files may be read twice.
+    #[clap(long)]
+    file_sha1: bool,
 
     /// Specify tracker URLs. Use this option multiple times to specify
     /// mutiple tiers. Use comma to split trackers in the same tier.
@@ -79,16 +205,45 @@ struct CliOptions {
     /// Mark torrent as private.
     #[clap(long)]
     private: bool,
+    /// Free-form comment stored in the torrent.
+    #[clap(short, long)]
+    comment: Option<String>,
+    /// Source tag stored in the info dict, required by some private
+    /// trackers. Changes the info hash.
+    #[clap(short, long)]
+    source: Option<String>,
+    /// Custom info dict entry as key=value, or key=int:value for an integer.
+    /// Changes the info hash. Use this option multiple times to add
+    /// multiple keys.
+    #[clap(long)]
+    info_key: Vec<String>,
+    /// Custom root dict entry as key=value, or key=int:value for an integer.
+    /// Use this option multiple times to add multiple keys.
+    #[clap(long)]
+    root_key: Vec<String>,
+    /// Do not store the creation date. Set SOURCE_DATE_EPOCH for
+    /// reproducible dates instead.
+    #[clap(long)]
+    no_date: bool,
     /// WebSeed(BEP19) URLs. Use this option can be used multiple times.
     #[clap(long)]
     webseed: Vec<String>,
 
-    /// A level of verbosity, and can be used multiple times.
-    #[clap(short, long, parse(from_occurrences))]
-    verbose: i32,
     /// use multiple thread for hash computation.
     #[clap(long, default_value = "1")]
     threads: u64,
+    /// How to read the files: mmap, read (pread into a buffer, a
+    /// truncated file is reported instead of crashing) or direct (read
+    /// with O_DIRECT, bypassing the page cache; Linux only).
+    #[clap(long, default_value = "mmap",
+           possible_values = &["mmap", "read", "direct"])]
+    io: IoMode,
+    /// Print the info hashes as JSON.
+    #[clap(long)]
+    json: bool,
+    /// Print a magnet link for the created torrent.
+    #[clap(long)]
+    magnet: bool,
 
     /// (debug) stop after dir walk.
     #[clap(long)]
@@ -98,145 +253,495 @@ struct CliOptions {
     stop_after_hash: bool,
 }
 
-impl CliOptions {
-    fn check(&self) -> Result<(), String> {
-        if self.no_bep3 && self.no_bep52 {
-            return Err("At least one bep3/bep52".into());
-        }
-        if self.announce.is_empty() && self.node.is_empty() {
-            return Err(
-                "Please specify tracker/node URL using --announce/--node"
-                    .into(),
-            );
-        }
-        if let Some(x) = self.piece_size {
-            if x < 16 * 1024 {
-                return Err("Piece size too small".into());
-            }
-            if x > 2 * 1024 * 1024 {
-                return Err("Piece size too large".into());
+#[derive(Clap, Debug)]
+struct VerifyOptions {
+    /// Torrent file to check against.
+    torrent: String,
+    /// The file or folder the torrent was created from.
+    data: String,
+    /// use multiple thread for hash computation.
+    #[clap(long, default_value = "1")]
+    threads: u64,
+    /// How to read the files: mmap, read (pread into a buffer, a
+    /// truncated file is reported instead of crashing) or direct (read
+    /// with O_DIRECT, bypassing the page cache; Linux only).
+    #[clap(long, default_value = "mmap",
+           possible_values = &["mmap", "read", "direct"])]
+    io: IoMode,
+}
+
+#[derive(Clap, Debug)]
+struct ShowOptions {
+    /// Torrent file to show.
+    torrent: String,
+    /// Also list BEP47 padding files.
+    #[clap(long)]
+    show_padding: bool,
+}
+
+#[derive(Clap, Debug)]
+struct EditOptions {
+    /// Torrent file to edit.
+    input: String,
+    /// Output torrent file. Can be the same as the input.
+    #[clap(short, long)]
+    output: String,
+
+    /// Replace all tracker tiers. Use this option multiple times to specify
+    /// mutiple tiers. Use comma to split trackers in the same tier.
+    #[clap(short, long)]
+    announce: Vec<String>,
+    /// Append a tracker tier, in the same format as --announce.
+    #[clap(long)]
+    add_announce: Vec<String>,
+    /// Remove a tracker URL from all tiers.
+    #[clap(long)]
+    remove_announce: Vec<String>,
+    /// Remove all trackers.
+    #[clap(long, conflicts_with = "announce")]
+    clear_announce: bool,
+    /// Replace all DHT nodes, as host:port.
+    #[clap(short, long)]
+    node: Vec<String>,
+    /// Append a DHT node.
+    #[clap(long)]
+    add_node: Vec<String>,
+    /// Remove a DHT node.
+    #[clap(long)]
+    remove_node: Vec<String>,
+    /// Remove all DHT nodes.
+    #[clap(long, conflicts_with = "node")]
+    clear_nodes: bool,
+    /// Replace all WebSeed(BEP19) URLs.
+    #[clap(long)]
+    webseed: Vec<String>,
+    /// Append a WebSeed URL.
+    #[clap(long)]
+    add_webseed: Vec<String>,
+    /// Remove a WebSeed URL.
+    #[clap(long)]
+    remove_webseed: Vec<String>,
+    /// Remove all WebSeed URLs.
+    #[clap(long, conflicts_with = "webseed")]
+    clear_webseeds: bool,
+    /// Set the comment.
+    #[clap(short, long)]
+    comment: Option<String>,
+    /// Remove the comment.
+    #[clap(long, conflicts_with = "comment")]
+    no_comment: bool,
+    /// Set the created by field.
+    #[clap(long)]
+    created_by: Option<String>,
+    /// Remove the created by field.
+    #[clap(long, conflicts_with = "created-by")]
+    no_created_by: bool,
+}
+
+#[derive(Clap, Debug)]
+struct RetargetOptions {
+    /// Torrent file to change.
+    input: String,
+    /// Output torrent file. Can be the same as the input.
+    #[clap(short, long)]
+    output: String,
+
+    /// Mark torrent as private.
+    #[clap(long)]
+    private: bool,
+    /// Clear the private flag.
+    #[clap(long, conflicts_with = "private")]
+    public: bool,
+    /// Set the source tag.
+    #[clap(short, long)]
+    source: Option<String>,
+    /// Remove the source tag.
+    #[clap(long, conflicts_with = "source")]
+    no_source: bool,
+    /// Rename the torrent root folder, or the file in single file mode.
+    #[clap(long)]
+    name: Option<String>,
+}
+
+// Each string is one tier, trackers in the same tier are comma separated.
+fn parse_announces(announce: &[String]) -> Vec<Vec<String>> {
+    announce
+        .iter()
+        .map(|s| s.split(',').map(str::to_string).collect())
+        .collect()
+}
+
+fn parse_nodes(node: &[String]) -> Result<Vec<(String, u16)>, Error> {
+    let mut nodes = vec![];
+    for host_port in node {
+        match split_host_port(host_port.as_str()) {
+            Some(x) => {
+                debug!("Node host={} port={}", x.0, x.1);
+                nodes.push(x)
             }
-            if (x & (x - 1)) != 0 {
-                return Err("Piece size is not a power of 2".into());
+            None => {
+                return Err(Error::InvalidOption(format!(
+                    "Invalid node: {}",
+                    host_port
+                )));
             }
         }
-        if self.threads < 1 {
-            return Err("are you kidding me running with 0 thread?".into());
-        }
-        if self.no_padding {
-            if !self.no_bep52 || self.threads != 1 || self.no_bep3 {
-                return Err(
-                    "no_padding is incompatible with bep52 and threads.".into(),
-                );
-            }
+    }
+    Ok(nodes)
+}
+
+fn create(opts: CreateOptions, verbose: i32) {
+    let nodes = parse_nodes(&opts.node).unwrap_or_else(|e| fail(e));
+    let mut builder = TorrentBuilder::new(&opts.input[0])
+        .private(opts.private)
+        .v1(!opts.no_bep3)
+        .v2(!opts.no_bep52)
+        .padding(!opts.no_padding)
+        .pad_last(opts.pad_last)
+        .pad_style(opts.pad_style)
+        .preserve_attrs(opts.preserve_attrs)
+        .file_sha1(opts.file_sha1)
+        .threads(opts.threads as u32)
+        .io(opts.io)
+        .torrentignore(!opts.no_torrentignore)
+        .include_hidden(opts.include_hidden)
+        .symlinks(opts.symlinks);
+    for input in &opts.input[1..] {
+        builder = builder.input(input);
+    }
+    if let Some(name) = &opts.name {
+        builder = builder.name(name);
+    }
+    if let Some(list) = &opts.files_from {
+        builder = builder.files_from(list);
+    }
+    for pattern in &opts.exclude {
+        builder = builder.exclude(pattern);
+    }
+    for path in &opts.exclude_from {
+        builder = builder.exclude_from(path);
+    }
+    for pattern in &opts.include {
+        builder = builder.include(pattern);
+    }
+    if let Some(x) = opts.pad_min_size {
+        builder = builder.pad_min_size(x);
+    }
+    if let Some(x) = opts.piece_size {
+        builder = builder.piece_size(x);
+    }
+    if let Some(comment) = &opts.comment {
+        builder = builder.comment(comment);
+    }
+    if opts.no_date {
+        builder = builder.creation_date(None);
+    }
+    if let Some(source) = &opts.source {
+        builder = builder.source(source);
+    }
+    for kv in &opts.info_key {
+        let (key, value) = parse_custom_key(kv).unwrap_or_else(|e| fail(e));
+        builder = builder.info_key(key, value);
+    }
+    for kv in &opts.root_key {
+        let (key, value) = parse_custom_key(kv).unwrap_or_else(|e| fail(e));
+        builder = builder.root_key(key, value);
+    }
+    if let Some(x) = opts.target_pieces {
+        builder =
+            builder.piece_size_policy(PieceSizePolicy::TargetPieceCount(x));
+    }
+    if let Some(x) = opts.max_torrent_size {
+        builder = builder.piece_size_policy(PieceSizePolicy::MaxTorrentSize(x));
+    }
+    let tiered_announces = parse_announces(&opts.announce);
+    debug!("Tiered announce URLs:\n{:#?}", tiered_announces);
+    for tier in tiered_announces {
+        builder = builder.announce_tier(tier);
+    }
+    for (host, port) in nodes {
+        builder = builder.node(host, port);
+    }
+    for url in &opts.webseed {
+        builder = builder.webseed(url);
+    }
+    builder.check().unwrap_or_else(|e| fail(e));
+
+    // Directory walk
+    let mut progress = ProgressIndicator::new(verbose > 0);
+    let walked_dir = builder.walk(&mut progress).unwrap_or_else(|e| fail(e));
+    if opts.stop_after_dirwalk {
+        return;
+    }
+
+    // Create torrent metadata and calc hash
+    let torrent = builder
+        .hash(walked_dir, &mut progress)
+        .unwrap_or_else(|e| fail(e));
+    if opts.stop_after_hash {
+        return;
+    }
+
+    // Write file
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&opts.output)
+        .and_then(|mut file| file.write_all(torrent.to_bytes().as_slice()))
+        .unwrap_or_else(|e| fail(Error::io(&opts.output, e)));
+
+    let hashes = torrent.info_hashes();
+    if opts.json {
+        println!("{}", hashes.to_json());
+    } else {
+        print!("{}", hashes);
+    }
+    if opts.magnet {
+        println!("{}", torrent.magnet_uri());
+    }
+}
+
+fn load_torrent(path: &str) -> (BencodeValue, MetaInfo) {
+    let parsed = std::fs::read(path)
+        .map_err(|e| Error::io(path, e))
+        .and_then(|d| Ok(BencodeValue::deserialize(&d)?))
+        .and_then(|root| {
+            let meta = MetaInfo::from_bencode(&root)?;
+            Ok((root, meta))
+        });
+    match parsed {
+        Ok(x) => x,
+        // Io errors already name the file
+        Err(e @ Error::Io { .. }) => fail(e),
+        Err(e) => {
+            error!("{}: {}", path, e);
+            exit(exit_code(&e));
         }
-        Ok(())
     }
+}
 
-    fn parse_announces(&self) -> Vec<Vec<String>> {
-        self.announce
-            .iter()
-            .map(|s| s.split(',').map(str::to_string).collect())
-            .collect()
+fn verify(opts: VerifyOptions, verbose: i32) {
+    if opts.threads < 1 {
+        fail(Error::InvalidOption(
+            "are you kidding me running with 0 thread?".into(),
+        ));
     }
+    let (_, meta) = load_torrent(&opts.torrent);
+
+    let mut progress = ProgressIndicator::new(verbose > 0);
+    let reports = match verify::verify(
+        &meta,
+        Path::new(&opts.data),
+        opts.threads as u32,
+        opts.io,
+        &mut progress,
+    ) {
+        Ok(r) => r,
+        Err(e) => fail(e),
+    };
 
-    fn parse_nodes(&self) -> Result<Vec<(String, u16)>, String> {
-        let mut nodes = vec![];
-        for host_port in &self.node {
-            match split_host_port(host_port.as_str()) {
-                Some(x) => {
-                    debug!("Node host={} port={}", x.0, x.1);
-                    nodes.push(x)
+    let mut failed = 0;
+    for r in &reports {
+        let path = r.path.display();
+        match r.status {
+            FileStatus::Ok => {
+                debug!("OK {}", path);
+                continue;
+            }
+            FileStatus::Missing => println!("MISSING {}", path),
+            FileStatus::SizeMismatch(len) => {
+                println!("SIZE MISMATCH {} ({} bytes on disk)", path, len)
+            }
+            FileStatus::Corrupted => {
+                println!("CORRUPTED {}", path);
+                if !r.bad_v1_pieces.is_empty() {
+                    println!("  v1 pieces failed: {:?}", r.bad_v1_pieces);
+                }
+                if !r.bad_v2_pieces.is_empty() {
+                    println!("  v2 pieces failed: {:?}", r.bad_v2_pieces);
                 }
-                None => {
-                    return Err(format!("Invalid node: {}", host_port));
+                if r.bad_v2_root {
+                    println!("  v2 pieces root mismatch");
                 }
             }
         }
-        Ok(nodes)
+        failed += 1;
+    }
+    println!("{} of {} files failed", failed, reports.len());
+    if failed > 0 {
+        exit(1);
     }
 }
 
+fn show(opts: ShowOptions) {
+    let (root, meta) = load_torrent(&opts.torrent);
+    let hashes = InfoHashes::from_torrent(&root).unwrap();
+    print!(
+        "{}",
+        show::format_torrent(&meta, &hashes, opts.show_padding)
+    );
+}
+
+// `Some(vec![])` if `clear`, None if nothing is given.
+fn replacement<T>(values: Vec<T>, clear: bool) -> Option<Vec<T>> {
+    if clear {
+        Some(vec![])
+    } else if values.is_empty() {
+        None
+    } else {
+        Some(values)
+    }
+}
+
+// `Some(None)` if `remove`.
+fn set_or_remove(
+    value: Option<String>,
+    remove: bool,
+) -> Option<Option<String>> {
+    if remove {
+        Some(None)
+    } else {
+        value.map(Some)
+    }
+}
+
+fn edit(opts: EditOptions) {
+    let (mut root, _) = load_torrent(&opts.input);
+    let changes = Edit {
+        announces: replacement(
+            parse_announces(&opts.announce),
+            opts.clear_announce,
+        ),
+        add_announces: parse_announces(&opts.add_announce),
+        remove_announces: opts.remove_announce,
+        nodes: replacement(
+            parse_nodes(&opts.node).unwrap_or_else(|e| fail(e)),
+            opts.clear_nodes,
+        ),
+        add_nodes: parse_nodes(&opts.add_node).unwrap_or_else(|e| fail(e)),
+        remove_nodes: parse_nodes(&opts.remove_node)
+            .unwrap_or_else(|e| fail(e)),
+        webseeds: replacement(opts.webseed, opts.clear_webseeds),
+        add_webseeds: opts.add_webseed,
+        remove_webseeds: opts.remove_webseed,
+        comment: set_or_remove(opts.comment, opts.no_comment),
+        created_by: set_or_remove(opts.created_by, opts.no_created_by),
+    };
+    changes.apply(&mut root).unwrap_or_else(|e| fail(e));
+
+    let output = &opts.output;
+    std::fs::write(output, root.serialize())
+        .unwrap_or_else(|e| fail(Error::io(output, e)));
+    print!("{}", InfoHashes::from_torrent(&root).unwrap());
+}
+
+fn retarget(opts: RetargetOptions) {
+    let (mut root, _) = load_torrent(&opts.input);
+    let old_hashes = InfoHashes::from_torrent(&root).unwrap();
+    let changes = Retarget {
+        private: match (opts.private, opts.public) {
+            (true, _) => Some(true),
+            (_, true) => Some(false),
+            _ => None,
+        },
+        source: set_or_remove(opts.source, opts.no_source),
+        name: opts.name,
+    };
+    changes.apply(&mut root).unwrap_or_else(|e| fail(e));
+
+    let output = &opts.output;
+    std::fs::write(output, root.serialize())
+        .unwrap_or_else(|e| fail(Error::io(output, e)));
+    print!("Old:\n{}", old_hashes);
+    print!("New:\n{}", InfoHashes::from_torrent(&root).unwrap());
+}
+
+// Inserts `create` when the first argument that isn't a flag is not a
+// command, so that `mktorrent-rs <input> -o <output>` keeps working.
+fn with_default_command(mut args: Vec<OsString>) -> Vec<OsString> {
+    let app = CliOptions::into_app();
+    let first = args
+        .iter()
+        .skip(1)
+        .find(|a| !a.to_str().map(|a| a.starts_with('-')).unwrap_or(false));
+    if let Some(first) = first {
+        let is_command = first == "help"
+            || app.get_subcommands().any(|c| first == c.get_name());
+        if !is_command {
+            args.insert(1, "create".into());
+        }
+    }
+    args
+}
+
 fn main() {
-    // CLI option checks
-    let opts = CliOptions::parse();
+    let args = with_default_command(std::env::args_os().collect());
+    let opts = CliOptions::parse_from(args);
     stderrlog::new()
         .module(module_path!())
+        .module("mktorrent")
         .verbosity(opts.verbose as usize + 2)
         .init()
         .unwrap();
 
-    if let Err(e) = opts.check() {
-        error!("{}", e);
-        return;
+    match opts.command {
+        Command::Create(o) => create(o, opts.verbose),
+        Command::Verify(o) => verify(o, opts.verbose),
+        Command::Show(o) => show(o),
+        Command::Edit(o) => edit(o),
+        Command::Retarget(o) => retarget(o),
     }
-    let tiered_announces = opts.parse_announces();
-    debug!("Tiered announce URLs:\n{:#?}", tiered_announces);
-    let nodes = match opts.parse_nodes() {
-        Ok(n) => n,
-        Err(s) => {
-            error!("{}", s);
-            return;
-        }
-    };
+}
 
-    // Directory walk
-    let mut progress = ProgressIndicator::new(opts.verbose > 0);
-    let walked_dir = WalkedDir::walk(opts.input, &mut progress).unwrap();
-    if opts.stop_after_dirwalk {
-        return;
-    }
+#[cfg(test)]
+mod test {
+    use super::*;
 
-    // Create torrent metadata and calc hash
-    let meta = if opts.no_padding {
-        let mut torrent_meta = TorrentMetadataV1::new(
-            tiered_announces,
-            nodes,
-            opts.private,
-            opts.piece_size,
-            opts.webseed,
-            walked_dir,
+    #[test]
+    fn custom_key() {
+        assert_eq!(
+            parse_custom_key("k=v").unwrap(),
+            ("k".to_string(), BencodeValue::from("v"))
         );
-        torrent_meta.hash(&mut progress).unwrap()
-    } else {
-        let mut v2 = TorrentMetadataV2::new(
-            tiered_announces,
-            nodes,
-            opts.private,
-            opts.piece_size,
-            opts.webseed,
-            walked_dir,
+        assert_eq!(
+            parse_custom_key("k=a=b").unwrap(),
+            ("k".to_string(), BencodeValue::from("a=b"))
         );
-        v2.hash(
-            &mut progress,
-            opts.threads as u32,
-            !opts.no_bep3,
-            !opts.no_bep52,
-        )
-        .unwrap()
-    };
-    if opts.stop_after_hash {
-        return;
+        assert_eq!(
+            parse_custom_key("k=").unwrap(),
+            ("k".to_string(), BencodeValue::from(""))
+        );
+        assert_eq!(
+            parse_custom_key("k=int:-3").unwrap(),
+            ("k".to_string(), BencodeValue::from(-3))
+        );
+        assert!(parse_custom_key("k").is_err());
+        assert!(parse_custom_key("=v").is_err());
+        assert!(parse_custom_key("k=int:x").is_err());
     }
 
-    // Write file
-    let mut file = OpenOptions::new()
-        .write(true)
-        .create(true)
-        .truncate(true)
-        .open(opts.output)
-        .unwrap();
-    file.write_all(meta.serialize().as_slice()).unwrap();
-
-    // let mut total_size: u64 = 0;
-    // for file_meta in &torrent_meta.files {
-    //     println!("{}", file_meta.path.to_str().unwrap());
-    //     for comp in &file_meta.path_components {
-    //         println!("  ==> {}", comp);
-    //     }
-    //     println!("  size: {}", file_meta.fsize);
-    //     println!("  sha1: {}", file_meta.sha1);
-    //     total_size += file_meta.fsize;
-    // }
-    // println!("Total size: {}", total_size);
+    #[test]
+    fn default_command() {
+        let args = |a: &[&str]| -> Vec<OsString> {
+            a.iter().map(OsString::from).collect()
+        };
+        assert_eq!(
+            with_default_command(args(&["m", "in", "-o", "out"])),
+            args(&["m", "create", "in", "-o", "out"])
+        );
+        assert_eq!(
+            with_default_command(args(&["m", "-v", "-o", "out", "in"])),
+            args(&["m", "create", "-v", "-o", "out", "in"])
+        );
+        for a in &[
+            &["m", "create", "in"][..],
+            &["m", "-v", "verify", "t", "in"],
+            &["m", "help"],
+            &["m", "--help"],
+            &["m"],
+        ] {
+            assert_eq!(with_default_command(args(a)), args(a));
+        }
+    }
 }
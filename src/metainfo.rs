@@ -0,0 +1,525 @@
+use crate::bencode::BencodeValue;
+use crate::error::{Error, Result};
+
+use std::collections::BTreeMap;
+
+// Parse errors are plain messages, wrapped into Error::InvalidTorrent.
+type ParseResult<T> = std::result::Result<T, String>;
+
+/// Name prefix of padding files written by BitComet and libtorrent 1.x,
+/// followed by the index of the padding file and an underscore.
+pub const LEGACY_PAD_PREFIX: &str = "_____padding_file_";
+
+/// One entry of the torrent's file list, padding files included.
+pub struct MetaFile {
+    // Path relative to the torrent root. Empty in single file mode.
+    pub path: Vec<String>,
+    pub length: u64,
+    // BEP47 attributes, empty if absent.
+    pub attr: String,
+    // BEP52 merkle root, only for non-empty files of v2 torrents.
+    pub pieces_root: Option<Vec<u8>>,
+    // BEP47 symlink target relative to the torrent root.
+    pub symlink_path: Option<Vec<String>>,
+}
+
+impl MetaFile {
+    /// Padding files are marked with attr "p" (BEP47). Older clients only
+    /// name them `_____padding_file_<N>_`, at the root of the torrent.
+    pub fn is_padding(&self) -> bool {
+        let legacy_name = match self.path.as_slice() {
+            [name] => name
+                .strip_prefix(LEGACY_PAD_PREFIX)
+                .and_then(|n| n.strip_suffix('_'))
+                .map(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+                .unwrap_or(false),
+            _ => false,
+        };
+        self.attr.contains('p') || legacy_name
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.attr.contains('l')
+    }
+}
+
+/// Read-side view of a .torrent file, as written by `torrent_meta.rs` and
+/// `torrent_meta_v2.rs`.
+pub struct MetaInfo {
+    pub name: String,
+    pub piece_length: u64,
+    pub private: bool,
+    pub meta_version: Option<i64>,
+    // Concatenated BEP3 SHA-1 piece hashes, None for v2-only torrents.
+    pub pieces: Option<Vec<u8>>,
+    // The v1 file list if present, the flattened v2 file tree otherwise.
+    pub files: Vec<MetaFile>,
+    // pieces root => concatenated piece layer hashes
+    pub piece_layers: BTreeMap<Vec<u8>, Vec<u8>>,
+    // BEP12 tiers, or a single tier holding "announce"
+    pub announces: Vec<Vec<String>>,
+    pub nodes: Vec<(String, u16)>,
+    pub webseeds: Vec<String>,
+    // Info dict "source", set for some private trackers
+    pub source: Option<String>,
+    pub comment: Option<String>,
+    pub created_by: Option<String>,
+    // Seconds since the Unix epoch
+    pub creation_date: Option<i64>,
+}
+
+fn get_int(dict: &BencodeValue, key: &str) -> ParseResult<Option<i64>> {
+    match dict.get(key.as_bytes()) {
+        None => Ok(None),
+        Some(v) => v
+            .as_integer()
+            .map(Some)
+            .ok_or(format!("\"{}\" is not an integer", key)),
+    }
+}
+
+fn get_str(dict: &BencodeValue, key: &str) -> ParseResult<Option<String>> {
+    match dict.get(key.as_bytes()) {
+        None => Ok(None),
+        Some(v) => v
+            .as_str()
+            .map(|s| Some(s.to_string()))
+            .ok_or(format!("\"{}\" is not a UTF-8 string", key)),
+    }
+}
+
+fn get_length(dict: &BencodeValue) -> ParseResult<u64> {
+    match get_int(dict, "length")? {
+        Some(l) if l >= 0 => Ok(l as u64),
+        Some(l) => Err(format!("Invalid file length {}", l)),
+        None => Err("File entry has no length".into()),
+    }
+}
+
+// Path components end up joined to a local directory, make sure none of
+// them can escape it.
+fn check_component(c: &str) -> ParseResult<()> {
+    if c.is_empty()
+        || c == "."
+        || c == ".."
+        || c.contains('/')
+        || c.contains('\\')
+        || c.contains('\0')
+    {
+        return Err(format!("Unsafe path component {:?}", c));
+    }
+    Ok(())
+}
+
+fn get_symlink_path(entry: &BencodeValue) -> ParseResult<Option<Vec<String>>> {
+    let list = match entry.get(b"symlink path") {
+        None => return Ok(None),
+        Some(l) => l.as_list().ok_or("\"symlink path\" is not a list")?,
+    };
+    let path = string_list(list, "symlink path")?;
+    for c in &path {
+        check_component(c)?;
+    }
+    Ok(Some(path))
+}
+
+fn string_list(list: &[BencodeValue], what: &str) -> ParseResult<Vec<String>> {
+    list.iter()
+        .map(|v| {
+            v.as_str()
+                .map(str::to_string)
+                .ok_or(format!("Invalid \"{}\" entry", what))
+        })
+        .collect()
+}
+
+fn parse_announces(root: &BencodeValue) -> ParseResult<Vec<Vec<String>>> {
+    if let Some(tiers) = root.get(b"announce-list") {
+        let tiers = tiers.as_list().ok_or("\"announce-list\" is not a list")?;
+        let mut ret = vec![];
+        for tier in tiers {
+            let tier =
+                tier.as_list().ok_or("Invalid \"announce-list\" tier")?;
+            ret.push(string_list(tier, "announce-list")?);
+        }
+        return Ok(ret);
+    }
+    Ok(get_str(root, "announce")?
+        .map(|a| vec![vec![a]])
+        .unwrap_or_default())
+}
+
+fn parse_nodes(root: &BencodeValue) -> ParseResult<Vec<(String, u16)>> {
+    let nodes = match root.get(b"nodes") {
+        None => return Ok(vec![]),
+        Some(n) => n.as_list().ok_or("\"nodes\" is not a list")?,
+    };
+    let mut ret = vec![];
+    for node in nodes {
+        match node.as_list().map(Vec::as_slice) {
+            Some([host, port]) => match (host.as_str(), port.as_integer()) {
+                (Some(host), Some(port)) if (0..=65535).contains(&port) => {
+                    ret.push((host.to_string(), port as u16))
+                }
+                _ => return Err("Invalid \"nodes\" entry".into()),
+            },
+            _ => return Err("Invalid \"nodes\" entry".into()),
+        }
+    }
+    Ok(ret)
+}
+
+fn parse_webseeds(root: &BencodeValue) -> ParseResult<Vec<String>> {
+    match root.get(b"url-list") {
+        None => Ok(vec![]),
+        Some(BencodeValue::List(l)) => string_list(l, "url-list"),
+        Some(_) => Ok(vec![get_str(root, "url-list")?.unwrap()]),
+    }
+}
+
+fn parse_v1_files(info: &BencodeValue) -> ParseResult<Vec<MetaFile>> {
+    let list = match info.get(b"files") {
+        // Single file mode
+        None => {
+            return Ok(vec![MetaFile {
+                path: vec![],
+                length: get_length(info)?,
+                attr: get_str(info, "attr")?.unwrap_or_default(),
+                pieces_root: None,
+                symlink_path: get_symlink_path(info)?,
+            }])
+        }
+        Some(l) => l.as_list().ok_or("\"files\" is not a list")?,
+    };
+    let mut files = vec![];
+    for entry in list {
+        let mut path = vec![];
+        for c in entry
+            .get(b"path")
+            .and_then(BencodeValue::as_list)
+            .ok_or("File entry has no path list")?
+        {
+            let c = c.as_str().ok_or("Path is not a UTF-8 string")?;
+            check_component(c)?;
+            path.push(c.to_string());
+        }
+        if path.is_empty() {
+            return Err("File entry has an empty path".into());
+        }
+        files.push(MetaFile {
+            path,
+            length: get_length(entry)?,
+            attr: get_str(entry, "attr")?.unwrap_or_default(),
+            pieces_root: None,
+            symlink_path: get_symlink_path(entry)?,
+        });
+    }
+    Ok(files)
+}
+
+fn walk_file_tree(
+    node: &BTreeMap<Vec<u8>, BencodeValue>,
+    path: &mut Vec<String>,
+    files: &mut Vec<MetaFile>,
+) -> ParseResult<()> {
+    for (k, v) in node {
+        let child = v.as_map().ok_or("File tree node is not a dict")?;
+        if k.is_empty() {
+            if path.is_empty() {
+                return Err("File tree has a file without name".into());
+            }
+            let pieces_root = match v.get(b"pieces root") {
+                None => None,
+                Some(r) => match r.as_bytes() {
+                    Some(r) if r.len() == 32 => Some(r.to_vec()),
+                    _ => return Err("Invalid \"pieces root\"".into()),
+                },
+            };
+            files.push(MetaFile {
+                path: path.clone(),
+                length: get_length(v)?,
+                attr: get_str(v, "attr")?.unwrap_or_default(),
+                pieces_root,
+                symlink_path: get_symlink_path(v)?,
+            });
+            continue;
+        }
+        let c = std::str::from_utf8(k)
+            .map_err(|_| "Path is not a UTF-8 string".to_string())?;
+        check_component(c)?;
+        path.push(c.to_string());
+        walk_file_tree(child, path, files)?;
+        path.pop();
+    }
+    Ok(())
+}
+
+fn parse_v2_files(info: &BencodeValue) -> ParseResult<Vec<MetaFile>> {
+    let tree = info
+        .get(b"file tree")
+        .and_then(BencodeValue::as_map)
+        .ok_or("\"file tree\" is not a dict")?;
+    let mut files = vec![];
+    walk_file_tree(tree, &mut vec![], &mut files)?;
+    if files.is_empty() {
+        return Err("\"file tree\" is empty".into());
+    }
+    Ok(files)
+}
+
+impl MetaInfo {
+    pub fn from_bencode(root: &BencodeValue) -> Result<MetaInfo> {
+        Self::parse(root).map_err(Error::InvalidTorrent)
+    }
+
+    /// Decodes and parses the content of a .torrent file.
+    pub fn from_bytes(data: &[u8]) -> Result<MetaInfo> {
+        Self::from_bencode(&BencodeValue::deserialize(data)?)
+    }
+
+    fn parse(root: &BencodeValue) -> ParseResult<MetaInfo> {
+        let info = root.get(b"info").ok_or("Missing \"info\" dict")?;
+        if info.as_map().is_none() {
+            return Err("\"info\" is not a dict".into());
+        }
+        let name = get_str(info, "name")?.ok_or("Missing \"name\"")?;
+        check_component(&name)?;
+        let piece_length = match get_int(info, "piece length")? {
+            Some(l) if l > 0 => l as u64,
+            _ => return Err("Missing or invalid \"piece length\"".into()),
+        };
+        let private = get_int(info, "private")?.unwrap_or(0) == 1;
+        let meta_version = get_int(info, "meta version")?;
+        let pieces = match info.get(b"pieces") {
+            None => None,
+            Some(p) => Some(
+                p.as_bytes()
+                    .filter(|p| p.len() % 20 == 0)
+                    .ok_or("Invalid \"pieces\"")?
+                    .to_vec(),
+            ),
+        };
+
+        let v2_files = if meta_version == Some(2) {
+            if piece_length < 16 * 1024 || !piece_length.is_power_of_two() {
+                return Err("Invalid \"piece length\" for v2".into());
+            }
+            Some(parse_v2_files(info)?)
+        } else {
+            None
+        };
+        let files = match (&pieces, v2_files) {
+            (None, None) => return Err("Neither v1 nor v2 metadata".into()),
+            (None, Some(mut v2)) => {
+                // BEP52 single file mode: the only file is named after
+                // the torrent.
+                if v2.len() == 1 && v2[0].path == [name.as_str()] {
+                    v2[0].path.clear();
+                }
+                v2
+            }
+            (Some(_), v2) => {
+                let mut v1 = parse_v1_files(info)?;
+                if let Some(v2) = v2 {
+                    // Hybrid torrent, both lists must describe the same
+                    // files in the same order.
+                    let mut v2_iter = v2.into_iter();
+                    for f in v1.iter_mut().filter(|f| !f.is_padding()) {
+                        match v2_iter.next() {
+                            Some(v2f)
+                                if (v2f.path == f.path
+                                    || f.path.is_empty()
+                                        && v2f.path == [name.as_str()])
+                                    && v2f.length == f.length =>
+                            {
+                                f.pieces_root = v2f.pieces_root;
+                            }
+                            _ => {
+                                return Err("v1 and v2 file lists differ".into())
+                            }
+                        }
+                    }
+                    if v2_iter.next().is_some() {
+                        return Err("v1 and v2 file lists differ".into());
+                    }
+                }
+                v1
+            }
+        };
+
+        if let Some(p) = &pieces {
+            let total: u64 = files.iter().map(|f| f.length).sum();
+            let expected = if total == 0 {
+                0
+            } else {
+                (total - 1) / piece_length + 1
+            };
+            if p.len() as u64 / 20 != expected {
+                return Err(format!(
+                    "Expecting {} pieces, found {}",
+                    expected,
+                    p.len() / 20
+                ));
+            }
+        }
+
+        let mut piece_layers = BTreeMap::new();
+        if let Some(layers) = root.get(b"piece layers") {
+            let layers =
+                layers.as_map().ok_or("\"piece layers\" is not a dict")?;
+            for (k, v) in layers {
+                let v = v.as_bytes().ok_or("Invalid \"piece layers\"")?;
+                piece_layers.insert(k.clone(), v.to_vec());
+            }
+        }
+
+        Ok(MetaInfo {
+            name,
+            piece_length,
+            private,
+            meta_version,
+            pieces,
+            files,
+            piece_layers,
+            announces: parse_announces(root)?,
+            nodes: parse_nodes(root)?,
+            webseeds: parse_webseeds(root)?,
+            source: get_str(info, "source")?,
+            comment: get_str(root, "comment")?,
+            created_by: get_str(root, "created by")?,
+            creation_date: get_int(root, "creation date")?,
+        })
+    }
+
+    pub fn has_v1(&self) -> bool {
+        self.pieces.is_some()
+    }
+
+    pub fn has_v2(&self) -> bool {
+        self.meta_version == Some(2)
+    }
+
+    pub fn total_length(&self) -> u64 {
+        self.files.iter().map(|f| f.length).sum()
+    }
+}
+
+#[cfg(test)]
+mod parse_test {
+    use super::*;
+
+    fn root(data: &[u8]) -> BencodeValue {
+        BencodeValue::deserialize(data).unwrap()
+    }
+
+    #[test]
+    fn announces() {
+        assert_eq!(parse_announces(&root(b"de")), Ok(vec![]));
+        assert_eq!(
+            parse_announces(&root(b"d8:announce2:a1e")),
+            Ok(vec![vec!["a1".to_string()]])
+        );
+        // announce-list wins over announce
+        assert_eq!(
+            parse_announces(&root(
+                b"d8:announce2:a113:announce-listl\
+                                   l2:a12:a2el2:a3eee"
+            )),
+            Ok(vec![
+                vec!["a1".to_string(), "a2".to_string()],
+                vec!["a3".to_string()]
+            ])
+        );
+        assert!(parse_announces(&root(b"d13:announce-listl2:a1ee")).is_err());
+        assert!(parse_announces(&root(b"d8:announcei1ee")).is_err());
+    }
+
+    #[test]
+    fn nodes() {
+        assert_eq!(
+            parse_nodes(&root(b"d5:nodesll1:hi6881eeee")),
+            Ok(vec![("h".to_string(), 6881)])
+        );
+        assert!(parse_nodes(&root(b"d5:nodesl1:hee")).is_err());
+        assert!(parse_nodes(&root(b"d5:nodesll1:heee")).is_err());
+        assert!(parse_nodes(&root(b"d5:nodesll1:h4:6881eee")).is_err());
+        assert!(parse_nodes(&root(b"d5:nodesll1:hi65536eeee")).is_err());
+        assert!(parse_nodes(&root(b"d5:nodesll1:hi1ei2eeee")).is_err());
+    }
+
+    #[test]
+    fn webseeds() {
+        assert_eq!(parse_webseeds(&root(b"de")), Ok(vec![]));
+        assert_eq!(
+            parse_webseeds(&root(b"d8:url-list2:w1e")),
+            Ok(vec!["w1".to_string()])
+        );
+        assert_eq!(
+            parse_webseeds(&root(b"d8:url-listl2:w12:w2ee")),
+            Ok(vec!["w1".to_string(), "w2".to_string()])
+        );
+        assert!(parse_webseeds(&root(b"d8:url-listi1ee")).is_err());
+        assert!(parse_webseeds(&root(b"d8:url-listli1eee")).is_err());
+    }
+
+    #[test]
+    fn single_file() {
+        let meta = MetaInfo::from_bytes(
+            b"d4:infod6:lengthi5e4:name1:a12:piece lengthi16384e\
+              6:pieces20:aaaaaaaaaaaaaaaaaaaaee",
+        )
+        .unwrap();
+        assert_eq!(meta.name, "a");
+        assert_eq!(meta.files.len(), 1);
+        assert!(meta.files[0].path.is_empty());
+        assert_eq!(meta.files[0].length, 5);
+        assert!(meta.has_v1() && !meta.has_v2());
+    }
+
+    #[test]
+    fn multi_file() {
+        let meta = MetaInfo::from_bytes(
+            b"d4:infod5:filesld6:lengthi5e4:pathl1:beed6:lengthi3e\
+              4:pathl1:c1:deee4:name1:a12:piece lengthi16384e\
+              6:pieces20:aaaaaaaaaaaaaaaaaaaaee",
+        )
+        .unwrap();
+        let paths: Vec<_> = meta.files.iter().map(|f| f.path.clone()).collect();
+        assert_eq!(paths, vec![vec!["b"], vec!["c", "d"]]);
+        assert_eq!(meta.total_length(), 8);
+        // Two pieces expected
+        assert!(MetaInfo::from_bytes(
+            b"d4:infod5:filesld6:lengthi16384e4:pathl1:beed6:lengthi3e\
+              4:pathl1:ceee4:name1:a12:piece lengthi16384e\
+              6:pieces20:aaaaaaaaaaaaaaaaaaaaee",
+        )
+        .is_err());
+        // Path escaping the torrent folder
+        assert!(MetaInfo::from_bytes(
+            b"d4:infod5:filesld6:lengthi5e4:pathl2:..1:beee4:name1:a\
+              12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaaee",
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn padding() {
+        let file = |path: &[&str], attr: &str| MetaFile {
+            path: path.iter().map(|c| c.to_string()).collect(),
+            length: 1,
+            attr: attr.to_string(),
+            pieces_root: None,
+            symlink_path: None,
+        };
+        assert!(file(&[".pad", "1"], "p").is_padding());
+        assert!(file(&["_____padding_file_0_"], "p").is_padding());
+        assert!(file(&["_____padding_file_12_"], "").is_padding());
+        assert!(!file(&[".pad", "1"], "").is_padding());
+        assert!(!file(&["d", "_____padding_file_0_"], "").is_padding());
+        assert!(!file(&["_____padding_file_"], "").is_padding());
+        assert!(!file(&["_____padding_file__"], "").is_padding());
+        assert!(!file(&["_____padding_file_0"], "").is_padding());
+        assert!(!file(&["_____padding_file_0_.txt"], "").is_padding());
+        assert!(!file(&["_____padding_file_x_"], "").is_padding());
+    }
+}
@@ -0,0 +1,202 @@
+use crate::dirwalker::DataFile;
+use crate::error::{Error, Result};
+
+pub const MIN_PIECE_SIZE: u64 = 16 * 1024;
+pub const MAX_PIECE_SIZE: u64 = 64 * 1024 * 1024;
+/// Largest piece size mainstream clients are known to accept. Larger pieces
+/// are allowed, but never selected automatically.
+pub const MAX_COMPATIBLE_PIECE_SIZE: u64 = 16 * 1024 * 1024;
+
+pub(crate) fn check_piece_size(x: u64) -> Result<()> {
+    if !(MIN_PIECE_SIZE..=MAX_PIECE_SIZE).contains(&x) || (x & (x - 1)) != 0 {
+        return Err(Error::InvalidPieceSize(x));
+    }
+    Ok(())
+}
+
+// Rough bencoded sizes of the parts of a torrent, used to estimate the
+// size of the .torrent file. Path names are added separately.
+const FIXED_BYTES: u64 = 256;
+const V1_FILE_ENTRY_BYTES: u64 = 32;
+const V1_PAD_ENTRY_BYTES: u64 = 48;
+const V2_FILE_ENTRY_BYTES: u64 = 96;
+const V2_LAYER_ENTRY_BYTES: u64 = 48;
+
+/// How to pick the piece size when it's not set explicitly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PieceSizePolicy {
+    /// Smallest piece size giving at most this many pieces.
+    TargetPieceCount(u64),
+    /// Smallest piece size keeping the .torrent file under this many bytes,
+    /// including the v2 piece layers.
+    MaxTorrentSize(u64),
+}
+
+impl Default for PieceSizePolicy {
+    fn default() -> Self {
+        PieceSizePolicy::TargetPieceCount(2048)
+    }
+}
+
+// (file length, bytes of the path components)
+type FileSize = (u64, u64);
+
+// Total # of pieces. If padded, every file starts on a piece boundary.
+fn piece_count(files: &[FileSize], piece_size: u64, padded: bool) -> u64 {
+    let pieces = |l: u64| if l == 0 { 0 } else { (l - 1) / piece_size + 1 };
+    if padded {
+        files.iter().map(|&(l, _)| pieces(l)).sum()
+    } else {
+        pieces(files.iter().map(|&(l, _)| l).sum())
+    }
+}
+
+fn torrent_size(
+    files: &[FileSize],
+    piece_size: u64,
+    v1: bool,
+    v2: bool,
+    padded: bool,
+) -> u64 {
+    let mut size = FIXED_BYTES;
+    for &(len, name_len) in files {
+        if v1 {
+            size += name_len + V1_FILE_ENTRY_BYTES;
+            if padded && len % piece_size != 0 {
+                size += V1_PAD_ENTRY_BYTES;
+            }
+        }
+        if v2 {
+            size += name_len + V2_FILE_ENTRY_BYTES;
+            if len > piece_size {
+                // piece layers, one hash per piece
+                size += V2_LAYER_ENTRY_BYTES
+                    + 32 * piece_count(&[(len, 0)], piece_size, true);
+            }
+        }
+    }
+    if v1 {
+        size += 20 * piece_count(files, piece_size, padded);
+    }
+    size
+}
+
+impl PieceSizePolicy {
+    /// Picks a piece size for `files`. `v1`/`v2` tell which metadata will
+    /// be written and `padded` whether files are aligned to pieces.
+    pub fn select(
+        &self,
+        files: &[DataFile],
+        v1: bool,
+        v2: bool,
+        padded: bool,
+    ) -> u64 {
+        let sizes: Vec<FileSize> = files
+            .iter()
+            .map(|f| {
+                let name_len =
+                    f.path_components.iter().map(|c| c.len() as u64 + 4).sum();
+                (f.len(), name_len)
+            })
+            .collect();
+        self.select_by_sizes(&sizes, v1, v2, padded)
+    }
+
+    fn select_by_sizes(
+        &self,
+        files: &[FileSize],
+        v1: bool,
+        v2: bool,
+        padded: bool,
+    ) -> u64 {
+        let (limit, metric): (u64, Box<dyn Fn(u64) -> u64>) = match *self {
+            PieceSizePolicy::TargetPieceCount(n) => {
+                (n, Box::new(|ps| piece_count(files, ps, padded || v2)))
+            }
+            PieceSizePolicy::MaxTorrentSize(n) => {
+                (n, Box::new(|ps| torrent_size(files, ps, v1, v2, padded)))
+            }
+        };
+        // Don't go for huge pieces if they don't help, e.g. lots of small
+        // files each taking at least one piece anyway.
+        let best = metric(MAX_COMPATIBLE_PIECE_SIZE);
+        let mut piece_size = MIN_PIECE_SIZE;
+        while piece_size < MAX_COMPATIBLE_PIECE_SIZE {
+            let m = metric(piece_size);
+            if m <= limit || m <= best {
+                break;
+            }
+            piece_size *= 2;
+        }
+        piece_size
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MIB: u64 = 1024 * 1024;
+
+    #[test]
+    fn target_piece_count() {
+        let policy = PieceSizePolicy::TargetPieceCount(2048);
+        assert_eq!(
+            policy.select_by_sizes(&[(1024, 8)], true, true, true),
+            MIN_PIECE_SIZE
+        );
+        assert_eq!(
+            policy.select_by_sizes(&[(1024 * MIB, 8)], true, true, true),
+            512 * 1024
+        );
+        assert_eq!(
+            policy.select_by_sizes(&[(20 * 1024 * MIB, 8)], true, true, true),
+            16 * MIB
+        );
+        assert_eq!(
+            policy.select_by_sizes(&[(100 * 1024 * MIB, 8)], true, true, true),
+            MAX_COMPATIBLE_PIECE_SIZE
+        );
+    }
+
+    #[test]
+    fn many_small_files() {
+        // 10000 files of 10KiB each take one piece each at any size.
+        let files = vec![(10 * 1024, 16); 10000];
+        let policy = PieceSizePolicy::TargetPieceCount(2048);
+        assert_eq!(
+            policy.select_by_sizes(&files, true, true, true),
+            MIN_PIECE_SIZE
+        );
+        // Unless they are packed without padding.
+        assert_eq!(
+            policy.select_by_sizes(&files, true, false, false),
+            64 * 1024
+        );
+    }
+
+    #[test]
+    fn max_torrent_size() {
+        let files = [(1024 * MIB, 8)];
+        // v1: 20 bytes per piece
+        let policy = PieceSizePolicy::MaxTorrentSize(100 * 1024);
+        assert_eq!(
+            policy.select_by_sizes(&files, true, false, false),
+            256 * 1024
+        );
+        // hybrid: 20 + 32 bytes per piece
+        assert_eq!(
+            policy.select_by_sizes(&files, true, true, true),
+            1024 * 1024
+        );
+    }
+
+    #[test]
+    fn check_range() {
+        assert!(check_piece_size(8 * 1024).is_err());
+        assert!(check_piece_size(16 * 1024).is_ok());
+        assert!(check_piece_size(64 * MIB).is_ok());
+        assert!(check_piece_size(128 * MIB).is_err());
+        assert!(check_piece_size(3 * MIB).is_err());
+    }
+}
@@ -0,0 +1,225 @@
+use crate::error::{Error, Result};
+
+use memmap2::MmapOptions;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+/// How the hashers read the input files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IoMode {
+    /// Map the files into memory. A file truncated while being hashed
+    /// crashes the process with SIGBUS.
+    #[default]
+    Mmap,
+    /// pread into a reusable buffer.
+    Read,
+    /// Same as `Read` with O_DIRECT, bypassing the page cache. Linux only,
+    /// and not supported by every file system.
+    Direct,
+}
+
+impl FromStr for IoMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "mmap" => Ok(IoMode::Mmap),
+            "read" => Ok(IoMode::Read),
+            "direct" => Ok(IoMode::Direct),
+            _ => Err(format!("Unknown I/O mode: {}", s)),
+        }
+    }
+}
+
+// Bytes read at once, a multiple of the 16KiB merkle blocks
+const BUF_SIZE: usize = 1024 * 1024;
+// Alignment of the buffer, offsets and lengths required by O_DIRECT
+const ALIGN: usize = 4096;
+
+fn truncated(path: &Path) -> Error {
+    Error::io(
+        path,
+        io::Error::new(io::ErrorKind::UnexpectedEof, "File truncated"),
+    )
+}
+
+#[cfg(unix)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    use std::os::unix::fs::FileExt;
+    file.read_at(buf, offset)
+}
+
+#[cfg(windows)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    use std::os::windows::fs::FileExt;
+    file.seek_read(buf, offset)
+}
+
+#[cfg(target_os = "linux")]
+fn set_direct(options: &mut OpenOptions) -> io::Result<()> {
+    use std::os::unix::fs::OpenOptionsExt;
+    options.custom_flags(libc::O_DIRECT);
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_direct(_options: &mut OpenOptions) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "O_DIRECT is only supported on Linux",
+    ))
+}
+
+/// Reads byte ranges of files with the chosen `IoMode`. Each hashing thread
+/// owns one, so that the buffer is reused across files.
+pub(crate) struct FileReader {
+    mode: IoMode,
+    // BUF_SIZE bytes starting at the first aligned address, empty for mmap
+    buf: Vec<u8>,
+}
+
+impl FileReader {
+    pub(crate) fn new(mode: IoMode) -> Self {
+        let buf = match mode {
+            IoMode::Mmap => vec![],
+            _ => vec![0; BUF_SIZE + ALIGN],
+        };
+        FileReader { mode, buf }
+    }
+
+    /// Passes the bytes `offset..offset + len` of the file at `path` to
+    /// `visit`, in one or more chunks. Fails if the file is shorter.
+    pub(crate) fn read<F>(
+        &mut self,
+        path: &Path,
+        offset: u64,
+        len: u64,
+        mut visit: F,
+    ) -> Result<()>
+    where
+        F: FnMut(&[u8]),
+    {
+        if len == 0 {
+            return Ok(());
+        }
+        let end = offset + len;
+        let mut options = OpenOptions::new();
+        options.read(true);
+        let direct = self.mode == IoMode::Direct;
+        if direct {
+            set_direct(&mut options).map_err(|e| Error::io(path, e))?;
+        }
+        let file = options.open(path).map_err(|e| Error::io(path, e))?;
+
+        if self.mode == IoMode::Mmap {
+            // Checked up front, mapped pages past the end raise SIGBUS.
+            let file_len =
+                file.metadata().map_err(|e| Error::io(path, e))?.len();
+            if file_len < end {
+                return Err(truncated(path));
+            }
+            let mmap = unsafe { MmapOptions::new().map(&file) }
+                .map_err(|e| Error::io(path, e))?;
+            visit(&mmap[offset as usize..end as usize]);
+            return Ok(());
+        }
+
+        let start = self.buf.as_ptr().align_offset(ALIGN);
+        let buf = &mut self.buf[start..start + BUF_SIZE];
+        // O_DIRECT reads whole blocks, skip the bytes before `offset`.
+        let mut skip = if direct { offset as usize % ALIGN } else { 0 };
+        let mut pos = offset - skip as u64;
+        while pos < end {
+            let mut want = std::cmp::min(BUF_SIZE as u64, end - pos) as usize;
+            if direct {
+                want = want.div_ceil(ALIGN) * ALIGN;
+            }
+            let mut filled = 0;
+            while filled < want {
+                let at = pos + filled as u64;
+                match read_at(&file, &mut buf[filled..want], at) {
+                    Ok(0) => break,
+                    Ok(n) => filled += n,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {
+                        continue
+                    }
+                    Err(e) => return Err(Error::io(path, e)),
+                }
+                // Only the end of the file gives a partial block.
+                if direct && filled % ALIGN != 0 {
+                    break;
+                }
+            }
+            let useful = std::cmp::min(filled as u64, end - pos) as usize;
+            if useful <= skip || (filled < want && pos + (filled as u64) < end)
+            {
+                return Err(truncated(path));
+            }
+            visit(&buf[skip..useful]);
+            pos += want as u64;
+            skip = 0;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod reader_test {
+    use super::*;
+    use crate::test_util::{data, TempDir};
+
+    #[test]
+    fn truncated_file() {
+        let dir = TempDir::new("reader-truncated");
+        let data = data(3 * BUF_SIZE);
+        let path = dir.write("a", &data);
+        for &mode in &[IoMode::Mmap, IoMode::Read] {
+            let mut reader = FileReader::new(mode);
+            let mut out = vec![];
+            let (offset, len) = (1000, 2 * BUF_SIZE as u64 + 7);
+            reader
+                .read(&path, offset, len, |d| out.extend_from_slice(d))
+                .unwrap();
+            assert_eq!(out, &data[1000..1000 + len as usize]);
+            let len = data.len() as u64;
+            assert!(reader.read(&path, 1, len, |_| ()).is_err());
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn direct() {
+        let dir = TempDir::new("reader-direct");
+        // Ends with a partial block
+        let data = data(3 * BUF_SIZE + 100);
+        let path = dir.write("a", &data);
+        let total = data.len() as u64;
+        let mut reader = FileReader::new(IoMode::Direct);
+        let cases = [
+            (1000, 2 * BUF_SIZE as u64 + 7),
+            (ALIGN as u64, BUF_SIZE as u64),
+            (5, 10),
+            (0, total),
+            (total - 3, 3),
+        ];
+        for &(offset, len) in &cases {
+            let mut out = vec![];
+            match reader.read(&path, offset, len, |d| out.extend_from_slice(d))
+            {
+                Err(Error::Io { source, .. })
+                    if source.raw_os_error() == Some(libc::EINVAL) =>
+                {
+                    // The file system doesn't support O_DIRECT.
+                    return;
+                }
+                r => r.unwrap(),
+            }
+            let (l, r) = (offset as usize, (offset + len) as usize);
+            assert_eq!(out, &data[l..r]);
+        }
+        assert!(reader.read(&path, 1, total, |_| ()).is_err());
+        assert!(reader.read(&path, total, 1, |_| ()).is_err());
+    }
+}
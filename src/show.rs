@@ -0,0 +1,183 @@
+use crate::infohash::{to_hex, InfoHashes};
+use crate::metainfo::MetaInfo;
+
+use indicatif::HumanBytes;
+use std::fmt::Write;
+
+/// Renders a human readable description of the torrent. BEP47 padding
+/// files are left out unless `show_padding` is set.
+pub fn format_torrent(
+    meta: &MetaInfo,
+    hashes: &InfoHashes,
+    show_padding: bool,
+) -> String {
+    let mut out = String::new();
+    // Writing into a String never fails.
+    let _ = write_torrent(&mut out, meta, hashes, show_padding);
+    out
+}
+
+fn write_torrent(
+    out: &mut String,
+    meta: &MetaInfo,
+    hashes: &InfoHashes,
+    show_padding: bool,
+) -> std::fmt::Result {
+    let version = match (meta.has_v1(), meta.has_v2()) {
+        (true, true) => "hybrid (v1 + v2)",
+        (false, true) => "v2",
+        _ => "v1",
+    };
+    writeln!(out, "Name:         {}", meta.name)?;
+    writeln!(
+        out,
+        "Piece length: {} ({})",
+        meta.piece_length,
+        HumanBytes(meta.piece_length)
+    )?;
+    writeln!(out, "Meta version: {}", version)?;
+    writeln!(
+        out,
+        "Private:      {}",
+        if meta.private { "yes" } else { "no" }
+    )?;
+    if let Some(s) = &meta.source {
+        writeln!(out, "Source:       {}", s)?;
+    }
+    if let Some(c) = &meta.comment {
+        writeln!(out, "Comment:      {}", c)?;
+    }
+    if let Some(c) = &meta.created_by {
+        writeln!(out, "Created by:   {}", c)?;
+    }
+    if let Some(d) = meta.creation_date {
+        writeln!(out, "Created on:   {}", d)?;
+    }
+    if let Some(h) = &hashes.v1 {
+        writeln!(out, "Info hash v1: {}", to_hex(h))?;
+    }
+    if let Some(h) = &hashes.v2 {
+        writeln!(out, "Info hash v2: {}", to_hex(h))?;
+    }
+
+    if !meta.announces.is_empty() {
+        writeln!(out, "Trackers:")?;
+        for (idx, tier) in meta.announces.iter().enumerate() {
+            writeln!(out, "  Tier {}:", idx + 1)?;
+            for url in tier {
+                writeln!(out, "    {}", url)?;
+            }
+        }
+    }
+    if !meta.nodes.is_empty() {
+        writeln!(out, "DHT nodes:")?;
+        for (host, port) in &meta.nodes {
+            if host.contains(':') {
+                writeln!(out, "  [{}]:{}", host, port)?;
+            } else {
+                writeln!(out, "  {}:{}", host, port)?;
+            }
+        }
+    }
+    if !meta.webseeds.is_empty() {
+        writeln!(out, "URL list:")?;
+        for url in &meta.webseeds {
+            writeln!(out, "  {}", url)?;
+        }
+    }
+
+    let files: Vec<_> = meta
+        .files
+        .iter()
+        .filter(|f| show_padding || !f.is_padding())
+        .collect();
+    let data_files = meta.files.iter().filter(|f| !f.is_padding());
+    writeln!(
+        out,
+        "Files: {} ({})",
+        data_files.clone().count(),
+        HumanBytes(data_files.map(|f| f.length).sum())
+    )?;
+    let single_file = meta.files.len() == 1 && meta.files[0].path.is_empty();
+    if !single_file {
+        writeln!(out, "  {}/", meta.name)?;
+    }
+    // Directories printed for the previous file
+    let mut dirs: &[String] = &[];
+    for f in files {
+        let (name, parents) = match f.path.split_last() {
+            Some(x) => x,
+            None => (&meta.name, &[][..]),
+        };
+        let common = dirs
+            .iter()
+            .zip(parents.iter())
+            .take_while(|(a, b)| a == b)
+            .count();
+        for (depth, d) in parents.iter().enumerate().skip(common) {
+            writeln!(out, "  {}{}/", "  ".repeat(depth + 1), d)?;
+        }
+        dirs = parents;
+
+        let depth = if single_file { 0 } else { parents.len() + 1 };
+        write!(out, "  {}{} ({} bytes)", "  ".repeat(depth), name, f.length)?;
+        if !f.attr.is_empty() {
+            write!(out, " [attr: {}]", f.attr)?;
+        }
+        if let Some(target) = &f.symlink_path {
+            write!(out, " -> {}", target.join("/"))?;
+        }
+        if let Some(root) = &f.pieces_root {
+            write!(out, " {}", to_hex(root))?;
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod show_test {
+    use super::*;
+    use crate::test_util::{data, TempDir};
+    use crate::{PadStyle, TorrentBuilder};
+
+    fn show(dir: &TempDir, style: PadStyle, attr: bool) -> (String, String) {
+        let torrent = TorrentBuilder::new(dir.path())
+            .announce("http://tracker/")
+            .piece_size(16 * 1024)
+            .pad_style(style)
+            .build()
+            .unwrap();
+        let mut meta = MetaInfo::from_bytes(&torrent.to_bytes()).unwrap();
+        if !attr {
+            for f in &mut meta.files {
+                f.attr.clear();
+            }
+        }
+        let hashes = torrent.info_hashes();
+        (
+            format_torrent(&meta, &hashes, false),
+            format_torrent(&meta, &hashes, true),
+        )
+    }
+
+    #[test]
+    fn padding() {
+        let dir = TempDir::new("show-padding");
+        dir.write("a", &data(100));
+        dir.write("b", &data(100));
+        let cases = [
+            (PadStyle::Bep47, true, ".pad/"),
+            (PadStyle::Legacy, true, "_____padding_file_"),
+            // Older clients only name the padding files.
+            (PadStyle::Legacy, false, "_____padding_file_"),
+        ];
+        for &(style, attr, pad_name) in &cases {
+            let (hidden, shown) = show(&dir, style, attr);
+            assert!(hidden.contains("Files: 2 (200B)"), "{}", hidden);
+            assert!(!hidden.contains(pad_name), "{}", hidden);
+            assert!(shown.contains("Files: 2 (200B)"), "{}", shown);
+            assert!(shown.contains(pad_name), "{}", shown);
+        }
+    }
+}
@@ -0,0 +1,60 @@
+//! Helpers shared by the unit tests.
+
+use crate::dirwalker::DataFile;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Folder under the system temp dir, unique to the process and the test so
+/// that concurrent runs don't collide. Removed when dropped.
+pub(crate) struct TempDir(PathBuf);
+
+impl TempDir {
+    pub(crate) fn new(test: &str) -> Self {
+        let path = std::env::temp_dir().join(format!(
+            "mktorrent-rs-{}-{}",
+            std::process::id(),
+            test
+        ));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.0
+    }
+
+    /// Writes `data` to `rel`, creating the parent folders.
+    pub(crate) fn write(&self, rel: &str, data: &[u8]) -> PathBuf {
+        let path = self.0.join(rel);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, data).unwrap();
+        path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// `len` bytes that don't repeat within a piece.
+pub(crate) fn data(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 + i / 4099) as u8).collect()
+}
+
+/// Entry of the existing file `path`, at `components` in the torrent.
+pub(crate) fn data_file<P: Into<PathBuf>>(
+    path: P,
+    components: &[&str],
+) -> DataFile {
+    let path = path.into();
+    DataFile {
+        metadata: fs::metadata(&path).unwrap(),
+        path,
+        path_components: components.iter().map(|c| c.to_string()).collect(),
+        symlink_path: None,
+    }
+}
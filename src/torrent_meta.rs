@@ -1,24 +1,264 @@
 use crate::bencode::BencodeValue;
 use crate::dirwalker::{DataFile, WalkedDir};
+use crate::error::{Error, Result};
+use crate::metainfo::LEGACY_PAD_PREFIX;
+use crate::piece_size::{check_piece_size, PieceSizePolicy};
 use crate::progress::ProgressIndicator;
+use crate::reader::{FileReader, IoMode};
+use crate::torrent_meta_v2::FileSha1;
 
+use crossbeam::queue::SegQueue;
+use crossbeam::scope;
 use log::*;
-use memmap2::MmapOptions;
 use sha1::{Digest, Sha1};
 use std::collections::BTreeMap;
-use std::fs::File;
-use std::io;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
 
-struct Bep3Hasher {
+#[cfg(unix)]
+fn is_executable(f: &DataFile) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    f.metadata.permissions().mode() & 0o111 != 0
+}
+
+#[cfg(not(unix))]
+fn is_executable(_f: &DataFile) -> bool {
+    false
+}
+
+/// Optional BEP47 keys of the file entries.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct FileOptions {
+    // Executable and hidden attributes
+    pub(crate) preserve_attrs: bool,
+    // Whole-file SHA-1, only written in the v1 file list
+    pub(crate) sha1: bool,
+}
+
+/// Naming of the padding files in the v1 file list. Both are marked with
+/// attr "p".
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PadStyle {
+    /// `.pad/<length>`, as suggested by BEP47.
+    #[default]
+    Bep47,
+    /// `_____padding_file_<index>_` in the root folder, as written by
+    /// BitComet and libtorrent 1.x.
+    Legacy,
+}
+
+impl FromStr for PadStyle {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "bep47" => Ok(PadStyle::Bep47),
+            "legacy" => Ok(PadStyle::Legacy),
+            _ => Err(format!("Unknown padding style: {}", s)),
+        }
+    }
+}
+
+/// How BEP47 padding files are laid out.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct PaddingOptions {
+    // Also pad the last data file up to the piece boundary
+    pub(crate) pad_last: bool,
+    // Only align files larger than this, v1 only. Smaller files share
+    // pieces with their neighbours.
+    pub(crate) min_size: Option<u64>,
+    pub(crate) style: PadStyle,
+}
+
+/// Keys added to the torrent besides the generated ones.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ExtraKeys {
+    pub(crate) root: BTreeMap<Vec<u8>, BencodeValue>,
+    pub(crate) info: BTreeMap<Vec<u8>, BencodeValue>,
+}
+
+/// Adds the BEP47 keys of `f` to its entry in `files` or the file tree.
+pub(crate) fn insert_file_attrs(
+    entry: &mut BTreeMap<Vec<u8>, BencodeValue>,
+    f: &DataFile,
+    options: FileOptions,
+    sha1: Option<&[u8]>,
+) {
+    let mut attr = String::new();
+    if options.preserve_attrs {
+        let name = f.path_components.last().unwrap();
+        if name.starts_with('.') {
+            attr.push('h');
+        }
+        if f.symlink_path.is_none() && is_executable(f) {
+            attr.push('x');
+        }
+    }
+    if f.symlink_path.is_some() {
+        attr.push('l');
+    }
+    if !attr.is_empty() {
+        entry.insert(b"attr".to_vec(), BencodeValue::from(attr.as_str()));
+    }
+    if let Some(target) = &f.symlink_path {
+        entry.insert(
+            b"symlink path".to_vec(),
+            BencodeValue::List(
+                target
+                    .iter()
+                    .map(|c| BencodeValue::from(c.as_str()))
+                    .collect(),
+            ),
+        );
+    }
+    if let Some(sha1) = sha1 {
+        entry.insert(b"sha1".to_vec(), BencodeValue::Bytes(sha1.to_vec()));
+    }
+}
+
+/// Entry of the `index`-th padding file of `len` bytes in the v1 file
+/// list. BEP47
+pub(crate) fn pad_file_entry(
+    len: u64,
+    index: usize,
+    style: PadStyle,
+) -> BencodeValue {
+    let path = match style {
+        PadStyle::Bep47 => vec![".pad".to_string(), format!("{}", len)],
+        PadStyle::Legacy => vec![format!("{}{}_", LEGACY_PAD_PREFIX, index)],
+    };
+    let mut pad_file = BTreeMap::new();
+    pad_file.insert(b"attr".to_vec(), BencodeValue::from("p"));
+    pad_file.insert(b"length".to_vec(), BencodeValue::from(len as i64));
+    pad_file.insert(
+        b"path".to_vec(),
+        BencodeValue::List(
+            path.iter()
+                .map(|c| BencodeValue::from(c.as_str()))
+                .collect(),
+        ),
+    );
+    BencodeValue::Map(pad_file)
+}
+
+// Padding bytes after each file, so that every file larger than
+// `min_size` starts at a piece boundary.
+fn padding_before_large_files(
+    files: &[DataFile],
+    piece_size: u64,
+    options: PaddingOptions,
+) -> Vec<u64> {
+    let mut padding = vec![0; files.len()];
+    let min_size = match options.min_size {
+        Some(x) => x,
+        None => return padding,
+    };
+    // Single file mode has no file list to hold a padding file.
+    if files.len() == 1 && files[0].path_components.len() == 1 {
+        return padding;
+    }
+    let gap = |offset: u64| (piece_size - offset % piece_size) % piece_size;
+    let mut offset = 0;
+    // Last non-empty file, the padding goes right after it
+    let mut prev = None;
+    for (i, f) in files.iter().enumerate() {
+        if f.is_empty() {
+            continue;
+        }
+        if let Some(j) = prev {
+            if f.len() > min_size {
+                padding[j] = gap(offset);
+                offset += padding[j];
+            }
+        }
+        offset += f.len();
+        prev = Some(i);
+    }
+    if let (true, Some(j)) = (options.pad_last, prev) {
+        padding[j] = gap(offset);
+    }
+    padding
+}
+
+/// Adds the tracker, DHT node and WebSeed keys to the root dict.
+pub(crate) fn insert_trackers(
+    ret: &mut BTreeMap<Vec<u8>, BencodeValue>,
+    announces: &[Vec<String>],
+    nodes: &[(String, u16)],
+    webseeds: &[String],
+) {
+    // Empty tiers can come from the builder or an edit, leave them out.
+    let announces: Vec<&Vec<String>> =
+        announces.iter().filter(|t| !t.is_empty()).collect();
+    if let Some(tier) = announces.first() {
+        ret.insert(b"announce".to_vec(), BencodeValue::from(tier[0].as_str()));
+    }
+    if announces.len() > 1
+        || matches!(announces.first(), Some(t) if t.len() > 1)
+    {
+        // BEP 12
+        ret.insert(
+            b"announce-list".to_vec(),
+            BencodeValue::List(
+                announces
+                    .iter()
+                    .map(|tier| {
+                        BencodeValue::List(
+                            tier.iter()
+                                .map(|a| BencodeValue::from(a.as_str()))
+                                .collect(),
+                        )
+                    })
+                    .collect(),
+            ),
+        );
+    }
+    if !nodes.is_empty() {
+        // BEP 5
+        ret.insert(
+            b"nodes".to_vec(),
+            BencodeValue::List(
+                nodes
+                    .iter()
+                    .map(|(host, port)| {
+                        BencodeValue::List(vec![
+                            BencodeValue::from(host.as_str()),
+                            BencodeValue::from(*port as i64),
+                        ])
+                    })
+                    .collect(),
+            ),
+        );
+    }
+    if !webseeds.is_empty() {
+        // BEP 19
+        ret.insert(
+            b"url-list".to_vec(),
+            if webseeds.len() == 1 {
+                BencodeValue::from(webseeds[0].as_str())
+            } else {
+                BencodeValue::List(
+                    webseeds
+                        .iter()
+                        .map(|s| BencodeValue::from(s.as_str()))
+                        .collect(),
+                )
+            },
+        );
+    }
+}
+
+pub(crate) struct Bep3Hasher {
     piece_size: u64,
-    hashes: Vec<Vec<u8>>,
+    pub(crate) hashes: Vec<Vec<u8>>,
     hash_context: Sha1,
     // # of bytes of this piece that has been hashed into context
     hashed_size: u64,
 }
 
 impl Bep3Hasher {
-    fn new(piece_size: u64) -> Bep3Hasher {
+    pub(crate) fn new(piece_size: u64) -> Bep3Hasher {
         Bep3Hasher {
             piece_size,
             hashes: vec![],
@@ -27,7 +267,11 @@ impl Bep3Hasher {
         }
     }
 
-    fn visit_file(&mut self, data: &[u8], progress: &mut ProgressIndicator) {
+    pub(crate) fn visit_file(
+        &mut self,
+        data: &[u8],
+        progress: &mut ProgressIndicator,
+    ) {
         let mut offset = 0usize;
         let mut file_left = data.len();
         let mut piece_left = (self.piece_size - self.hashed_size) as usize;
@@ -53,7 +297,22 @@ impl Bep3Hasher {
         }
     }
 
-    fn visit_end(&mut self) {
+    // Feeds `len` zero bytes, e.g. for BEP47 padding files.
+    pub(crate) fn visit_zeros(
+        &mut self,
+        len: u64,
+        progress: &mut ProgressIndicator,
+    ) {
+        let zeros = vec![0u8; std::cmp::min(len, 1024 * 1024) as usize];
+        let mut left = len;
+        while left > 0 {
+            let n = std::cmp::min(left, zeros.len() as u64);
+            self.visit_file(&zeros[..n as usize], progress);
+            left -= n;
+        }
+    }
+
+    pub(crate) fn visit_end(&mut self) {
         if self.hashed_size > 0 {
             let raw_hash = self.hash_context.finalize_reset().to_vec();
             assert_eq!(20, raw_hash.len());
@@ -63,6 +322,21 @@ impl Bep3Hasher {
     }
 }
 
+// Concatenated piece hashes and the SHA-1 of each file, if enabled
+type PieceHashes = (Vec<u8>, Vec<Option<Vec<u8>>>);
+
+const V1_JOB_BYTES: u64 = 64 * 1024 * 1024; // 64MiB
+
+// Whole pieces of the concatenated files, hashed on one thread.
+struct PieceJob<'a> {
+    // index of the job, all jobs but the last have the same size
+    idx: u64,
+    // byte range in the concatenated files, starting at a piece boundary
+    offset: u64,
+    end: u64,
+    hashes: &'a mut [u8],
+}
+
 pub struct TorrentMetadata {
     files: Vec<DataFile>,
     announces: Vec<Vec<String>>,
@@ -70,66 +344,295 @@ pub struct TorrentMetadata {
     private: bool,
     nodes: Vec<(String, u16)>,
     webseeds: Vec<String>,
+    file_options: FileOptions,
+    // padding bytes after each file, BEP47
+    padding: Vec<u64>,
+    padding_style: PadStyle,
+    extra_keys: ExtraKeys,
 }
 
 impl TorrentMetadata {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         announces: Vec<Vec<String>>,
         nodes: Vec<(String, u16)>,
         private: bool,
         user_piece_size: Option<u64>,
         webseeds: Vec<String>,
+        file_options: FileOptions,
+        padding_options: PaddingOptions,
+        extra_keys: ExtraKeys,
         walked_dir: WalkedDir,
-    ) -> Self {
+    ) -> Result<Self> {
         if let Some(x) = user_piece_size {
-            assert!((x & (x - 1)) == 0);
-            assert!(x >= 16 * 1024);
-            assert!(x <= 2 * 1024 * 1024);
+            check_piece_size(x)?;
         }
-        assert!(!announces.is_empty() || !nodes.is_empty());
+        if announces.is_empty() && nodes.is_empty() {
+            return Err(Error::InvalidOption(
+                "Please specify tracker/node URL".into(),
+            ));
+        }
+        if walked_dir.files.is_empty() {
+            return Err(Error::EmptyInput);
+        }
+
+        let piece_size = user_piece_size.unwrap_or_else(|| {
+            PieceSizePolicy::default().select(
+                &walked_dir.files,
+                true,
+                false,
+                false,
+            )
+        });
+
+        let padding = padding_before_large_files(
+            &walked_dir.files,
+            piece_size,
+            padding_options,
+        );
 
-        TorrentMetadata {
+        Ok(TorrentMetadata {
             files: walked_dir.files,
             announces,
-            piece_size: user_piece_size.unwrap_or(0),
+            piece_size,
             private,
             nodes,
             webseeds,
-        }
+            file_options,
+            padding,
+            padding_style: padding_options.style,
+            extra_keys,
+        })
     }
 
-    pub fn hash(
-        &mut self,
+    // Hashes the files one by one on the current thread.
+    fn hash_files(
+        &self,
+        io: IoMode,
         progress: &mut ProgressIndicator,
-    ) -> io::Result<BencodeValue> {
-        // Determine piece size
-        let total_size = self.files.iter().map(|f| f.metadata.len()).sum();
-        self.piece_size = if self.piece_size != 0 {
-            // Don't change if piece size is pre-set
-            self.piece_size
-        } else if total_size < 50 * 1024 * 1024 * 1024 {
-            // Use 256KB for < 50GB
-            256 * 1024
-        } else {
-            // Use 1MB for >= 50GB
-            1024 * 1024
-        };
-
-        // Compute piece hashes
+    ) -> Result<PieceHashes> {
+        let total_size = self.files.iter().map(|f| f.len()).sum::<u64>()
+            + self.padding.iter().sum::<u64>();
         progress.hash_begin(total_size);
         let mut hasher = Bep3Hasher::new(self.piece_size);
-        for file_meta in &self.files {
-            if file_meta.metadata.len() == 0 {
+        let mut reader = FileReader::new(io);
+        let mut file_sha1s = vec![];
+        for (file_meta, &padding) in self.files.iter().zip(&self.padding) {
+            let mut sha1 =
+                if self.file_options.sha1 && file_meta.symlink_path.is_none() {
+                    Some(Sha1::new())
+                } else {
+                    None
+                };
+            if file_meta.is_empty() {
                 hasher.visit_file(b"", progress);
             } else {
-                debug!("Hashing {}...", file_meta.entry.path().display());
-                let f = File::open(&file_meta.entry.path())?;
-                let mmap = unsafe { MmapOptions::new().map(&f)? };
-                hasher.visit_file(mmap.as_ref(), progress);
+                debug!("Hashing {}...", file_meta.path.display());
+                reader.read(&file_meta.path, 0, file_meta.len(), |data| {
+                    hasher.visit_file(data, progress);
+                    if let Some(sha1) = &mut sha1 {
+                        sha1.update(data);
+                    }
+                })?;
             }
+            hasher.visit_zeros(padding, progress);
+            file_sha1s.push(sha1.map(|h| h.finalize().to_vec()));
         }
         hasher.visit_end();
         progress.hash_end();
+        Ok((hasher.hashes.concat(), file_sha1s))
+    }
+
+    // Same as `hash_files` on `thread_num` threads. The files are seen as
+    // one stream cut into jobs of whole pieces, so pieces spanning several
+    // files are hashed by a single job.
+    fn hash_files_parallel(
+        &self,
+        thread_num: u32,
+        io: IoMode,
+        progress: &mut ProgressIndicator,
+    ) -> Result<PieceHashes> {
+        // Offset of each file in the stream
+        let mut starts = vec![];
+        let mut total_size = 0u64;
+        for (f, padding) in self.files.iter().zip(&self.padding) {
+            starts.push(total_size);
+            total_size += f.len() + padding;
+        }
+        let piece_count = total_size.div_ceil(self.piece_size);
+        let job_pieces = std::cmp::max(1, V1_JOB_BYTES / self.piece_size);
+        let job_bytes = job_pieces * self.piece_size;
+
+        let file_sha1s: Vec<Option<FileSha1>> = self
+            .files
+            .iter()
+            .map(|f| {
+                if self.file_options.sha1 && f.symlink_path.is_none() {
+                    Some(FileSha1::default())
+                } else {
+                    None
+                }
+            })
+            .collect();
+        let mut pieces = vec![0u8; (piece_count * 20) as usize];
+        let tasks = SegQueue::new();
+        for (idx, hashes) in
+            pieces.chunks_mut((job_pieces * 20) as usize).enumerate()
+        {
+            let offset = idx as u64 * job_bytes;
+            tasks.push(PieceJob {
+                idx: idx as u64,
+                offset,
+                end: std::cmp::min(offset + job_bytes, total_size),
+                hashes,
+            });
+        }
+
+        progress.hash_begin(total_size);
+        // Set when a worker fails, so that the others stop early.
+        let failed = AtomicBool::new(false);
+        let ret = scope(|s| {
+            let (progress_notify, progress_rx) = mpsc::channel();
+
+            // UI thread
+            s.spawn(|_| {
+                let progress_rx = progress_rx;
+                while let Ok(b) = progress_rx.recv() {
+                    progress.hash_progress(b);
+                }
+            });
+
+            // Worker threads
+            let mut workers = vec![];
+            for _ in 0..thread_num {
+                let progress = progress_notify.clone();
+                let (tasks, failed, starts) = (&tasks, &failed, &starts);
+                let file_sha1s = &file_sha1s;
+                workers.push(s.spawn(move |_| -> Result<()> {
+                    let mut reader = FileReader::new(io);
+                    while !failed.load(Ordering::Relaxed) {
+                        let job = match tasks.pop() {
+                            Some(j) => j,
+                            None => break,
+                        };
+                        let ret = self.hash_piece_job(
+                            job,
+                            starts,
+                            job_bytes,
+                            file_sha1s,
+                            failed,
+                            &mut reader,
+                            &progress,
+                        );
+                        if ret.is_err() {
+                            failed.store(true, Ordering::Relaxed);
+                            return ret;
+                        }
+                    }
+                    Ok(())
+                }));
+            }
+            drop(progress_notify);
+            workers
+                .into_iter()
+                .map(|w| w.join().unwrap())
+                .fold(Ok(()), Result::and)
+        })
+        .unwrap();
+        drop(tasks);
+        progress.hash_end();
+        ret?;
+
+        let file_sha1s = file_sha1s
+            .into_iter()
+            .map(|h| h.map(FileSha1::digest))
+            .collect();
+        Ok((pieces, file_sha1s))
+    }
+
+    // Hashes the pieces of `job`, reading from every file it overlaps.
+    #[allow(clippy::too_many_arguments)]
+    fn hash_piece_job(
+        &self,
+        job: PieceJob,
+        starts: &[u64],
+        job_bytes: u64,
+        file_sha1s: &[Option<FileSha1>],
+        failed: &AtomicBool,
+        reader: &mut FileReader,
+        progress: &mpsc::Sender<u64>,
+    ) -> Result<()> {
+        let mut hasher = Bep3Hasher::new(self.piece_size);
+        let mut quiet = ProgressIndicator::new(true);
+        // Last file starting at or before the job
+        let first = starts.partition_point(|&x| x <= job.offset) - 1;
+        let files = self.files[first..]
+            .iter()
+            .zip(&self.padding[first..])
+            .zip(&starts[first..])
+            .zip(&file_sha1s[first..]);
+        for (((f, &padding), &start), file_sha1) in files {
+            if start >= job.end {
+                break;
+            }
+            // Part of the padding after the file in this job
+            let pad_lo = std::cmp::max(job.offset, start + f.len());
+            let pad_hi = std::cmp::min(job.end, start + f.len() + padding);
+            let lo = std::cmp::max(job.offset, start) - start;
+            let hi = std::cmp::min(job.end, start + f.len()) - start;
+            if lo >= hi {
+                if pad_lo < pad_hi {
+                    hasher.visit_zeros(pad_hi - pad_lo, &mut quiet);
+                    let _ = progress.send(pad_hi - pad_lo);
+                }
+                continue;
+            }
+            let mut hash =
+                |reader: &mut FileReader, mut sha1: Option<&mut Sha1>| {
+                    reader.read(&f.path, lo, hi - lo, |data| {
+                        hasher.visit_file(data, &mut quiet);
+                        if let Some(sha1) = &mut sha1 {
+                            sha1.update(data);
+                        }
+                    })
+                };
+            let ret = match file_sha1 {
+                // Jobs before this one hold the earlier parts
+                Some(file_sha1) => file_sha1.hash_part(
+                    (job.idx - start / job_bytes) as usize,
+                    failed,
+                    reader,
+                    &f.path,
+                    lo,
+                    hi - lo,
+                    hash,
+                ),
+                None => hash(reader, None),
+            };
+            ret?;
+            let _ = progress.send(hi - lo);
+            if pad_lo < pad_hi {
+                hasher.visit_zeros(pad_hi - pad_lo, &mut quiet);
+                let _ = progress.send(pad_hi - pad_lo);
+            }
+        }
+        hasher.visit_end();
+        job.hashes.copy_from_slice(&hasher.hashes.concat());
+        Ok(())
+    }
+
+    pub fn hash(
+        &mut self,
+        progress: &mut ProgressIndicator,
+        thread_num: u32,
+        io: IoMode,
+    ) -> Result<BencodeValue> {
+        // Compute piece hashes
+        let (pieces, file_sha1s) = if thread_num > 1 {
+            self.hash_files_parallel(thread_num, io, progress)?
+        } else {
+            self.hash_files(io, progress)?
+        };
 
         // Assemble info struct
         let mut info = BTreeMap::new();
@@ -143,27 +646,31 @@ impl TorrentMetadata {
             // BEP 27
             info.insert(b"private".to_vec(), BencodeValue::from(1));
         }
-        info.insert(
-            b"pieces".to_vec(),
-            BencodeValue::Bytes(hasher.hashes.concat()),
-        );
+        info.insert(b"pieces".to_vec(), BencodeValue::Bytes(pieces));
         if self.files.len() == 1 && self.files[0].path_components.len() == 1 {
             // Single file mode
             let file = &self.files[0];
             info.insert(
                 b"length".to_vec(),
-                BencodeValue::from(file.metadata.len() as i64),
+                BencodeValue::from(file.len() as i64),
+            );
+            insert_file_attrs(
+                &mut info,
+                file,
+                self.file_options,
+                file_sha1s[0].as_deref(),
             );
         } else {
             // Multi file mode
             let mut files = vec![];
-            for f in &self.files {
+            let mut pad_count = 0;
+            let entries = self.files.iter().zip(&file_sha1s).zip(&self.padding);
+            for ((f, sha1), &padding) in entries {
                 let mut file = BTreeMap::new();
                 file.insert(
                     b"length".to_vec(),
-                    BencodeValue::from(f.metadata.len() as i64),
+                    BencodeValue::from(f.len() as i64),
                 );
-                assert!(f.path_components.len() > 1);
                 assert_eq!(name, f.path_components[0]);
                 let mut path_vec = vec![];
                 for idx in 1..f.path_components.len() {
@@ -172,71 +679,181 @@ impl TorrentMetadata {
                     ));
                 }
                 file.insert(b"path".to_vec(), BencodeValue::List(path_vec));
+                insert_file_attrs(
+                    &mut file,
+                    f,
+                    self.file_options,
+                    sha1.as_deref(),
+                );
                 files.push(BencodeValue::Map(file));
+                if padding > 0 {
+                    files.push(pad_file_entry(
+                        padding,
+                        pad_count,
+                        self.padding_style,
+                    ));
+                    pad_count += 1;
+                }
             }
             info.insert(b"files".to_vec(), BencodeValue::List(files));
         }
 
         // Assemble the torrent file structure
         let mut ret = BTreeMap::<Vec<u8>, BencodeValue>::new();
-        if !self.announces.is_empty() {
-            ret.insert(
-                b"announce".to_vec(),
-                BencodeValue::from(self.announces[0][0].as_str()),
-            );
-        }
-        if self.announces.len() > 1 || self.announces[0].len() > 1 {
-            // BEP 12
-            ret.insert(
-                b"announce-list".to_vec(),
-                BencodeValue::List(
-                    self.announces
-                        .iter()
-                        .map(|tier| {
-                            BencodeValue::List(
-                                tier.iter()
-                                    .map(|a| BencodeValue::from(a.as_str()))
-                                    .collect(),
-                            )
-                        })
-                        .collect(),
-                ),
-            );
-        }
-        if !self.nodes.is_empty() {
-            // BEP 5
-            ret.insert(
-                b"nodes".to_vec(),
-                BencodeValue::List(
-                    self.nodes
-                        .iter()
-                        .map(|(host, port)| {
-                            BencodeValue::List(vec![
-                                BencodeValue::from(host.as_str()),
-                                BencodeValue::from(*port as i64),
-                            ])
-                        })
-                        .collect(),
-                ),
-            );
-        }
-        if !self.webseeds.is_empty() {
-            // BEP 19
-            ret.insert(
-                b"url-list".to_vec(),
-                if self.webseeds.len() == 1 {
-                    BencodeValue::from(self.webseeds[0].as_str())
-                } else {
-                    BencodeValue::List(
-                        self.webseeds
-                            .iter()
-                            .map(|s| BencodeValue::from(s.as_str()))
-                            .collect(),
-                    )
-                },
-            );
-        }
+        insert_trackers(&mut ret, &self.announces, &self.nodes, &self.webseeds);
+        info.extend(self.extra_keys.info.clone());
+        ret.extend(self.extra_keys.root.clone());
         ret.insert(b"info".to_vec(), BencodeValue::Map(info));
         Ok(BencodeValue::Map(ret))
     }
 }
+
+#[cfg(test)]
+mod tracker_test {
+    use super::*;
+
+    #[test]
+    fn empty_tiers() {
+        let tiers = |t: &[&[&str]]| -> Vec<Vec<String>> {
+            t.iter()
+                .map(|t| t.iter().map(|a| a.to_string()).collect())
+                .collect()
+        };
+        let mut root = BTreeMap::new();
+        insert_trackers(&mut root, &tiers(&[&[], &["a"]]), &[], &[]);
+        assert_eq!(
+            BencodeValue::Map(root).serialize(),
+            b"d8:announce1:ae".to_vec()
+        );
+
+        let mut root = BTreeMap::new();
+        insert_trackers(
+            &mut root,
+            &tiers(&[&[], &["a"], &[], &["b"]]),
+            &[],
+            &[],
+        );
+        assert_eq!(
+            BencodeValue::Map(root).serialize(),
+            b"d8:announce1:a13:announce-listll1:ael1:beee".to_vec()
+        );
+
+        let mut root = BTreeMap::new();
+        insert_trackers(&mut root, &tiers(&[&[]]), &[], &[]);
+        assert!(root.is_empty());
+    }
+}
+
+#[cfg(test)]
+mod attr_test {
+    use super::*;
+    use crate::test_util::{data_file, TempDir};
+
+    fn attrs(f: &DataFile, preserve_attrs: bool) -> (Option<String>, bool) {
+        let options = FileOptions {
+            preserve_attrs,
+            sha1: false,
+        };
+        let mut entry = BTreeMap::new();
+        insert_file_attrs(&mut entry, f, options, None);
+        let attr = entry
+            .get(&b"attr"[..])
+            .map(|a| a.as_str().unwrap().to_string());
+        (attr, entry.contains_key(&b"symlink path"[..]))
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn attrs_emitted() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = TempDir::new("attrs");
+        let file = |name: &str, mode: u32| {
+            let path = dir.write(name, b"x");
+            let perms = std::fs::Permissions::from_mode(mode);
+            std::fs::set_permissions(&path, perms).unwrap();
+            data_file(path, &["t", name])
+        };
+        let plain = file("plain", 0o644);
+        let exec = file("exec", 0o755);
+        let hidden_exec = file(".hidden", 0o700);
+        let mut link = file("link", 0o755);
+        link.symlink_path = Some(vec!["plain".into()]);
+
+        assert_eq!(attrs(&plain, true), (None, false));
+        assert_eq!(attrs(&exec, true), (Some("x".into()), false));
+        assert_eq!(attrs(&hidden_exec, true), (Some("hx".into()), false));
+        assert_eq!(attrs(&link, true), (Some("l".into()), true));
+        // Symlinks are marked regardless of preserve_attrs
+        assert_eq!(attrs(&exec, false), (None, false));
+        assert_eq!(attrs(&hidden_exec, false), (None, false));
+        assert_eq!(attrs(&link, false), (Some("l".into()), true));
+    }
+}
+
+#[cfg(test)]
+mod parallel_test {
+    use super::*;
+    use crate::test_util::{data, data_file, TempDir};
+
+    fn write_file(dir: &TempDir, name: &str, len: usize) -> DataFile {
+        data_file(dir.write(name, &data(len)), &["d", name])
+    }
+
+    #[test]
+    fn same_as_sequential() {
+        const MIB: usize = 1024 * 1024;
+        let dir = TempDir::new("parallel-same-as-sequential");
+        // Jobs end in the middle of the third file
+        let files = vec![
+            write_file(&dir, "1", 40 * MIB + 3),
+            write_file(&dir, "2", 0),
+            write_file(&dir, "3", 30 * MIB + 5),
+            write_file(&dir, "4", 1),
+        ];
+        let padding_options = PaddingOptions {
+            pad_last: true,
+            min_size: Some(MIB as u64),
+            ..Default::default()
+        };
+        let padding =
+            padding_before_large_files(&files, MIB as u64, padding_options);
+        // The third file is aligned, the last one shares its piece
+        assert_eq!(padding, vec![MIB as u64 - 3, 0, 0, MIB as u64 - 6]);
+        let meta = TorrentMetadata {
+            files,
+            announces: vec![],
+            piece_size: MIB as u64,
+            private: false,
+            nodes: vec![],
+            webseeds: vec![],
+            file_options: FileOptions {
+                preserve_attrs: false,
+                sha1: true,
+            },
+            padding,
+            padding_style: PadStyle::Bep47,
+            extra_keys: ExtraKeys::default(),
+        };
+        let mut progress = ProgressIndicator::new(true);
+        let expected = meta.hash_files(IoMode::Read, &mut progress).unwrap();
+        let actual = meta
+            .hash_files_parallel(3, IoMode::Mmap, &mut progress)
+            .unwrap();
+        assert_eq!(expected.0.len(), 72 * 20);
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn single_file_pad_last() {
+        let dir = TempDir::new("parallel-single-file-pad-last");
+        let mut file = write_file(&dir, "a", 5);
+        file.path_components.remove(0);
+        let options = PaddingOptions {
+            pad_last: true,
+            min_size: Some(0),
+            ..Default::default()
+        };
+        let files = vec![file];
+        assert_eq!(padding_before_large_files(&files, 16384, options), [0]);
+    }
+}
@@ -1,24 +1,126 @@
 use crate::bencode::BencodeValue;
 use crate::dirwalker::*;
+use crate::error::{Error, Result};
+use crate::piece_size::{check_piece_size, PieceSizePolicy};
 use crate::progress::ProgressIndicator;
+use crate::reader::{FileReader, IoMode};
+use crate::torrent_meta::{
+    insert_file_attrs, insert_trackers, pad_file_entry, ExtraKeys, FileOptions,
+    PadStyle, PaddingOptions,
+};
 
 use crossbeam::queue::SegQueue;
 use crossbeam::scope;
 use indicatif::HumanDuration;
 use log::*;
-use memmap2::MmapOptions;
 use sha1::Sha1;
 use sha2::{Digest, Sha256};
 use std::collections::BTreeMap;
 use std::fmt::{Debug, Formatter};
-use std::fs::File;
-use std::io;
 use std::path::Path;
-use std::sync::mpsc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Condvar, Mutex};
 use std::thread;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
-struct HashJob<'a> {
+/// Whole-file SHA-1 (BEP47), updated with the same data as the piece
+/// hashes. The parts of a file may be hashed on different threads, but
+/// the SHA-1 needs them in order: the part whose turn it is takes the
+/// hasher and hands it back when done. Parts are queued in file order, so
+/// the one whose turn it is has always been taken by a worker already.
+pub(crate) struct FileSha1 {
+    // (index of the next part to feed, hasher unless a part holds it)
+    state: Mutex<(usize, Option<Sha1>)>,
+    turn: Condvar,
+}
+
+impl Default for FileSha1 {
+    fn default() -> Self {
+        FileSha1 {
+            state: Mutex::new((0, Some(Sha1::new()))),
+            turn: Condvar::new(),
+        }
+    }
+}
+
+impl FileSha1 {
+    /// Runs `hash` on part `part_idx` of the file, the bytes
+    /// `offset..offset + len` of `path`. `hash` feeds the data to the
+    /// SHA-1 it is given. When earlier parts are still being hashed, it
+    /// only computes the pieces, and the part is read again to update the
+    /// SHA-1 once its turn comes, so that other parts aren't held up.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn hash_part<F>(
+        &self,
+        part_idx: usize,
+        failed: &AtomicBool,
+        reader: &mut FileReader,
+        path: &Path,
+        offset: u64,
+        len: u64,
+        hash: F,
+    ) -> Result<()>
+    where
+        F: FnOnce(&mut FileReader, Option<&mut Sha1>) -> Result<()>,
+    {
+        if let Some(mut sha1) = self.try_take(part_idx) {
+            let ret = hash(reader, Some(&mut sha1));
+            self.put(sha1);
+            return ret;
+        }
+        hash(reader, None)?;
+        let mut sha1 = match self.take(part_idx, failed) {
+            Some(sha1) => sha1,
+            // Another worker failed and reports the error.
+            None => return Ok(()),
+        };
+        let ret = reader.read(path, offset, len, |data| sha1.update(data));
+        self.put(sha1);
+        ret
+    }
+
+    // Takes the hasher if it is the turn of `part_idx`.
+    fn try_take(&self, part_idx: usize) -> Option<Sha1> {
+        let mut state = self.state.lock().unwrap();
+        if state.0 == part_idx {
+            state.1.take()
+        } else {
+            None
+        }
+    }
+
+    // Waits for the turn of `part_idx` and takes the hasher. Returns None
+    // if `failed` got set while waiting.
+    fn take(&self, part_idx: usize, failed: &AtomicBool) -> Option<Sha1> {
+        let mut state = self.state.lock().unwrap();
+        while state.0 != part_idx {
+            if failed.load(Ordering::Relaxed) {
+                return None;
+            }
+            state = self
+                .turn
+                .wait_timeout(state, Duration::from_millis(100))
+                .unwrap()
+                .0;
+        }
+        state.1.take()
+    }
+
+    // Hands the hasher back and passes the turn to the next part.
+    fn put(&self, sha1: Sha1) {
+        let mut state = self.state.lock().unwrap();
+        state.0 += 1;
+        state.1 = Some(sha1);
+        self.turn.notify_all();
+    }
+
+    pub(crate) fn digest(self) -> Vec<u8> {
+        let sha1 = self.state.into_inner().unwrap().1.unwrap();
+        sha1.finalize().to_vec()
+    }
+}
+
+pub(crate) struct HashJob<'a> {
     file: &'a Path,
     // starting offset bytes, aligned to v1 boundary
     offset: u64,
@@ -32,10 +134,14 @@ struct HashJob<'a> {
     // v1_piece*piece_factor except last job of the file.
     v2_pieces: u64,
     v2_hash: &'a mut [u8],
+    // Fed with the data of the job if set, in order of job_idx.
+    file_sha1: Option<&'a FileSha1>,
+    // index of the job in the file
+    job_idx: usize,
 }
 
 impl Debug for HashJob<'_> {
-    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
             "HashJob[\
@@ -54,28 +160,30 @@ impl Debug for HashJob<'_> {
     }
 }
 
-struct FileMetadata {
-    file: DataFile,
+pub(crate) struct FileMetadata {
+    pub(crate) file: DataFile,
     // How many leaf pieces in the merkle tree
     merkle_piece_count: u64,
     // merkle_tree[0] is the hash of 16KiB blocks
     // merkle_tree[1] is the layer above, etc.
     // Length of each layer is a multiple of 32 (size of SHA256).
     // Hashs that don't cover actual data won't be stored.
-    merkle_tree: Vec<Vec<u8>>,
+    pub(crate) merkle_tree: Vec<Vec<u8>>,
     // How many piece for the sha1 pieces
-    hash_v1_piece_count: u64,
+    pub(crate) hash_v1_piece_count: u64,
     // Hash computed using BEP3 method.
     // Hashs that don't cover actual data won't be stored.
-    hash_v1: Vec<u8>,
+    pub(crate) hash_v1: Vec<u8>,
     // padding bytes after this file. BEP47
     padding: u64,
-    is_last_data_file: bool,
+    pub(crate) is_last_data_file: bool,
+    // Computed by the jobs if set.
+    pub(crate) file_sha1: Option<FileSha1>,
 }
 
 impl FileMetadata {
-    fn new(f: DataFile, piece_size: u64) -> Self {
-        let l = f.metadata.len();
+    pub(crate) fn new(f: DataFile, piece_size: u64) -> Self {
+        let l = f.len();
         if l == 0 {
             // Empty file is treated differently.
             FileMetadata {
@@ -86,6 +194,7 @@ impl FileMetadata {
                 hash_v1: vec![],
                 padding: 0,
                 is_last_data_file: false,
+                file_sha1: None,
             }
         } else {
             let merkle_piece_count = (l - 1) / (16 * 1024) + 1;
@@ -97,17 +206,330 @@ impl FileMetadata {
                 merkle_tree: vec![vec![0; (merkle_piece_count * 32) as usize]],
                 hash_v1_piece_count,
                 hash_v1: vec![0; (hash_v1_piece_count * 20) as usize],
-                padding: if l % piece_size == 0 {
+                padding: if l.is_multiple_of(piece_size) {
                     0
                 } else {
                     piece_size - (l % piece_size)
                 },
                 is_last_data_file: false,
+                file_sha1: None,
             }
         }
     }
 }
 
+const MAX_JOB_BYTES: u64 = 1024 * 1024 * 1024; // 1GiB
+const MERKLE_PIECE_SIZE: u64 = 16 * 1024; // 16KiB
+
+/// Splits every non-empty file into jobs of at most `MAX_JOB_BYTES` bytes.
+/// Each job writes into its own slice of the file's hash buffers.
+pub(crate) fn make_hash_jobs(
+    files: &mut [FileMetadata],
+    piece_size: u64,
+    piece_factor: u64,
+) -> SegQueue<HashJob<'_>> {
+    let tasks: SegQueue<HashJob> = SegQueue::new();
+    for f in files {
+        if f.file.is_empty() {
+            continue;
+        }
+        let num_tasks = (f.file.len() - 1) / MAX_JOB_BYTES + 1;
+        // # of piece for each task
+        let mut task_pieces = vec![];
+        for _ in 0..f.hash_v1_piece_count % num_tasks {
+            task_pieces.push(f.hash_v1_piece_count / num_tasks + 1);
+        }
+        for _ in (f.hash_v1_piece_count % num_tasks)..num_tasks {
+            task_pieces.push(f.hash_v1_piece_count / num_tasks);
+        }
+
+        let mut piece_offset = 0;
+        let mut v1_hash = f.hash_v1.as_mut_slice();
+        let mut v2_hash = f.merkle_tree.get_mut(0).unwrap().as_mut_slice();
+        let mut left_merkle_piece = f.merkle_piece_count;
+        for (idx, &this_v1_pieces) in task_pieces.iter().enumerate() {
+            if left_merkle_piece == 0 {
+                panic!("left_merkle_piece==0 {}", f.file.path.display());
+            }
+            let this_v2_pieces =
+                std::cmp::min(this_v1_pieces * piece_factor, left_merkle_piece);
+            let right_boundary = std::cmp::min(
+                (piece_offset + this_v1_pieces) * piece_size,
+                f.file.len(),
+            );
+            let data_len = right_boundary - piece_offset * piece_size;
+
+            let (this_v1_hash, rem_v1_hash) =
+                v1_hash.split_at_mut((this_v1_pieces * 20) as usize);
+            let (this_v2_hash, rem_v2_hash) =
+                v2_hash.split_at_mut((this_v2_pieces * 32) as usize);
+            v1_hash = rem_v1_hash;
+            v2_hash = rem_v2_hash;
+
+            let job = HashJob {
+                file: &f.file.path,
+                offset: piece_offset * piece_size,
+                data_len,
+                v1_pieces: this_v1_pieces,
+                v1_piece_size: piece_size,
+                v1_hash: this_v1_hash,
+                v1_last_hash_zero_fill: !((idx == task_pieces.len() - 1)
+                    && f.is_last_data_file),
+                v2_pieces: this_v2_pieces,
+                v2_hash: this_v2_hash,
+                file_sha1: f.file_sha1.as_ref(),
+                job_idx: idx,
+            };
+            debug!("{:?}", job);
+            tasks.push(job);
+
+            piece_offset += this_v1_pieces;
+            left_merkle_piece -= this_v2_pieces;
+        }
+    }
+    tasks
+}
+
+// Computes the v1 hashes and merkle leaves of one job, and feeds its data
+// to the whole-file `sha1` if given.
+fn hash_job(
+    job: &mut HashJob,
+    reader: &mut FileReader,
+    mut sha1: Option<&mut Sha1>,
+    piece_factor: u64,
+    progress: &mpsc::Sender<u64>,
+) -> Result<()> {
+    // local vars
+    // |----|----|----|----| one v1, four v2 pieces
+    // |-----------|         actual data
+    // |------------0000000| covered by one v1 hash
+    // |----|----|-|         covered by 3 v2 hashes
+    // ^-job.offset        ^-hash_rbound
+    let hash_rbound = job.offset + job.v1_pieces * job.v1_piece_size;
+    let data_rbound = job.offset + job.data_len;
+    let v1_hash = &mut *job.v1_hash;
+    let v2_hash = &mut *job.v2_hash;
+
+    let mut v1_hasher = Sha1::new();
+    let mut v2_hasher = Sha256::new();
+    let mut finished_v1_pieces = 0u64;
+    let mut finished_v2_pieces = 0u64;
+    // bytes hashed into the current 16kb block
+    let mut block_bytes = 0u64;
+
+    reader.read(job.file, job.offset, job.data_len, |mut data| {
+        let _ = progress.send(data.len() as u64);
+        if let Some(sha1) = &mut sha1 {
+            sha1.update(data);
+        }
+        while !data.is_empty() {
+            let bytes = std::cmp::min(
+                MERKLE_PIECE_SIZE - block_bytes,
+                data.len() as u64,
+            ) as usize;
+            v1_hasher.update(&data[..bytes]);
+            v2_hasher.update(&data[..bytes]);
+            data = &data[bytes..];
+            block_bytes += bytes as u64;
+
+            if block_bytes == MERKLE_PIECE_SIZE {
+                // Finished a v2 piece
+                let l = (finished_v2_pieces * 32) as usize;
+                let r = ((finished_v2_pieces + 1) * 32) as usize;
+                v2_hash[l..r]
+                    .copy_from_slice(v2_hasher.finalize_reset().as_slice());
+                finished_v2_pieces += 1;
+                block_bytes = 0;
+
+                // Also finished a v1 piece
+                if finished_v2_pieces.is_multiple_of(piece_factor) {
+                    let l = (finished_v1_pieces * 20) as usize;
+                    let r = ((finished_v1_pieces + 1) * 20) as usize;
+                    v1_hash[l..r]
+                        .copy_from_slice(v1_hasher.finalize_reset().as_slice());
+                    finished_v1_pieces += 1;
+                }
+            }
+        }
+    })?;
+    assert!(data_rbound <= hash_rbound);
+
+    if block_bytes != 0 {
+        // file end is not aligned to 16kb
+        // write the last sha256
+        let l = (finished_v2_pieces * 32) as usize;
+        let r = ((finished_v2_pieces + 1) * 32) as usize;
+        v2_hash[l..r].copy_from_slice(v2_hasher.finalize_reset().as_slice());
+        finished_v2_pieces += 1;
+    }
+
+    if data_rbound != hash_rbound {
+        // file end is not aligned to v1 hash boundary
+        // zero fill the remaining v1 hash for gap file
+        // and write the last sha1
+        if job.v1_last_hash_zero_fill {
+            v1_hasher.update(vec![0; (hash_rbound - data_rbound) as usize]);
+        }
+        let l = (finished_v1_pieces * 20) as usize;
+        let r = ((finished_v1_pieces + 1) * 20) as usize;
+        v1_hash[l..r].copy_from_slice(v1_hasher.finalize_reset().as_slice());
+        finished_v1_pieces += 1;
+    }
+
+    assert_eq!(finished_v1_pieces, job.v1_pieces);
+    assert_eq!(finished_v2_pieces, job.v2_pieces);
+    Ok(())
+}
+
+/// Runs all jobs on `thread_num` worker threads, filling in the v1 hashes
+/// and the leaf layer of the merkle trees.
+pub(crate) fn run_hash_jobs(
+    tasks: SegQueue<HashJob>,
+    total_bytes: u64,
+    piece_factor: u64,
+    thread_num: u32,
+    io: IoMode,
+    progress: &mut ProgressIndicator,
+) -> Result<()> {
+    progress.hash_begin(total_bytes);
+    // Set when a worker fails, so that the others stop early.
+    let failed = AtomicBool::new(false);
+    let ret = scope(|s| {
+        let (progress_notify, progress_rx) = mpsc::channel();
+
+        // UI thread
+        s.spawn(|_| {
+            // Rusty magic, moves rx inside without moving progress.
+            let progress_rx = progress_rx;
+            loop {
+                if let Ok(b) = progress_rx.recv() {
+                    trace!(
+                        "{:?} Received hash_progress: {}",
+                        thread::current().id(),
+                        b
+                    );
+                    progress.hash_progress(b);
+                } else {
+                    return;
+                }
+            }
+        });
+
+        // Worker threads
+        let mut workers = vec![];
+        for _ in 0..thread_num {
+            let progress = progress_notify.clone();
+            workers.push(s.spawn(|_| -> Result<()> {
+                let mut byte_count = 0u64;
+                // move progress into lambda
+                let progress = progress;
+                let mut reader = FileReader::new(io);
+                while !failed.load(Ordering::Relaxed) {
+                    let mut job = match tasks.pop() {
+                        Some(j) => j,
+                        None => break,
+                    };
+                    debug!("{:?} takes job {:?}", thread::current().id(), job);
+                    let ret = match job.file_sha1 {
+                        Some(file_sha1) => {
+                            let (file, offset, len) =
+                                (job.file, job.offset, job.data_len);
+                            file_sha1.hash_part(
+                                job.job_idx,
+                                &failed,
+                                &mut reader,
+                                file,
+                                offset,
+                                len,
+                                |reader, sha1| {
+                                    hash_job(
+                                        &mut job,
+                                        reader,
+                                        sha1,
+                                        piece_factor,
+                                        &progress,
+                                    )
+                                },
+                            )
+                        }
+                        None => hash_job(
+                            &mut job,
+                            &mut reader,
+                            None,
+                            piece_factor,
+                            &progress,
+                        ),
+                    };
+                    if let Err(e) = ret {
+                        failed.store(true, Ordering::Relaxed);
+                        return Err(e);
+                    }
+                    byte_count += job.data_len;
+                }
+                debug!(
+                    "{:?} processed {} bytes",
+                    thread::current().id(),
+                    byte_count
+                );
+                Ok(())
+            }));
+        }
+        drop(progress_notify);
+        // Join all workers, reporting the first error
+        workers
+            .into_iter()
+            .map(|w| w.join().unwrap())
+            .fold(Ok(()), Result::and)
+    })
+    .unwrap();
+    drop(tasks);
+    progress.hash_end();
+    ret
+}
+
+/// Builds the upper layers of the merkle tree of every file from the leaf
+/// hashes computed by `run_hash_jobs`.
+pub(crate) fn build_merkle_trees(files: &mut [FileMetadata]) {
+    let start = Instant::now();
+    let mut filler_sha256 = vec![vec![0; 32]];
+    for i in 1..50 {
+        // Covers up to 16 EiB. Really?!
+        let mut hasher = Sha256::new();
+        hasher.update(filler_sha256[i - 1].as_slice());
+        hasher.update(filler_sha256[i - 1].as_slice());
+        filler_sha256.push(hasher.finalize().to_vec());
+    }
+
+    for f in files {
+        if f.file.is_empty() || f.merkle_tree[0].len() == 32 {
+            continue;
+        }
+        for level in 0.. {
+            let src = f.merkle_tree.get(level).unwrap().as_slice();
+            assert!(src.len() != 32);
+            assert!(src.len() % 32 == 0);
+            let mut dst = vec![];
+            for i in 0..src.len() / 64 {
+                dst.extend_from_slice(
+                    Sha256::digest(&src[i * 64..(i + 1) * 64]).as_slice(),
+                );
+            }
+            if src.len() > 32 && src.len() % 64 != 0 {
+                let mut hasher = Sha256::new();
+                hasher.update(&src[(src.len() / 64) * 64..]);
+                hasher.update(filler_sha256.get(level).unwrap().as_slice());
+                dst.extend_from_slice(hasher.finalize().as_slice());
+            }
+            let dst_len = dst.len();
+            f.merkle_tree.push(dst);
+            if dst_len == 32 {
+                break;
+            }
+        }
+    }
+    info!("Merkle tree built in {}", HumanDuration(start.elapsed()));
+}
+
 pub struct TorrentMetadata {
     files: Vec<FileMetadata>,
     total_bytes: u64,
@@ -121,43 +543,48 @@ pub struct TorrentMetadata {
     private: bool,
     nodes: Vec<(String, u16)>,
     webseeds: Vec<String>,
+    file_options: FileOptions,
+    padding_style: PadStyle,
+    extra_keys: ExtraKeys,
     // meta version
 }
 
 impl TorrentMetadata {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         announces: Vec<Vec<String>>,
         nodes: Vec<(String, u16)>,
         private: bool,
         user_piece_size: Option<u64>,
         webseeds: Vec<String>,
+        file_options: FileOptions,
+        padding_options: PaddingOptions,
+        extra_keys: ExtraKeys,
         walked_dir: WalkedDir,
-    ) -> Self {
+    ) -> Result<Self> {
         if let Some(x) = user_piece_size {
-            assert!((x & (x - 1)) == 0);
-            assert!(x >= 16 * 1024);
-            assert!(x <= 2 * 1024 * 1024);
+            check_piece_size(x)?;
         }
-        if walked_dir.files.is_empty() {
-            panic!("No file selected");
+        if announces.is_empty() && nodes.is_empty() {
+            return Err(Error::InvalidOption(
+                "Please specify tracker/node URL".into(),
+            ));
         }
-        let total_bytes =
-            walked_dir.files.iter().map(|e| e.metadata.len()).sum();
-        // TODO auto piece size selection
-        let piece_size = user_piece_size.unwrap_or(256 * 1024);
-        let mut piece_level = 0u64;
-        let mut tmp = 16 * 1024;
-        for level in 0.. {
-            if tmp == piece_size {
-                piece_level = level;
-                break;
-            } else if tmp < piece_size {
-                tmp *= 2;
-            } else {
-                panic!("incorrect piece size");
-            }
+        if walked_dir.files.is_empty() {
+            return Err(Error::EmptyInput);
         }
-        assert!(!announces.is_empty() || !nodes.is_empty());
+        let total_bytes = walked_dir.files.iter().map(|e| e.len()).sum();
+        let piece_size = user_piece_size.unwrap_or_else(|| {
+            PieceSizePolicy::default().select(
+                &walked_dir.files,
+                true,
+                true,
+                true,
+            )
+        });
+        // piece_size = 16KiB * 2^piece_level
+        let piece_level =
+            (piece_size / MERKLE_PIECE_SIZE).trailing_zeros() as u64;
 
         let mut ret = TorrentMetadata {
             files: walked_dir
@@ -168,19 +595,28 @@ impl TorrentMetadata {
             total_bytes,
             announces,
             piece_size,
-            piece_factor: 2u64.checked_pow(piece_level as u32).unwrap(),
+            piece_factor: piece_size / MERKLE_PIECE_SIZE,
             piece_level,
             private,
             nodes,
             webseeds,
+            file_options,
+            padding_style: padding_options.style,
+            extra_keys,
         };
-        for i in ret.files.len() - 1..=0 {
-            if ret.files[i].file.metadata.len() > 0 {
-                ret.files[i].is_last_data_file = true;
-                ret.files[i].padding = 0;
+        // Single file mode has no file list to hold a padding file.
+        let single_file = ret.files.len() == 1
+            && ret.files[0].file.path_components.len() == 1;
+        if !padding_options.pad_last || single_file {
+            // Nothing follows the last data file, so it needs no padding.
+            if let Some(f) =
+                ret.files.iter_mut().rev().find(|f| !f.file.is_empty())
+            {
+                f.is_last_data_file = true;
+                f.padding = 0;
             }
         }
-        ret
+        Ok(ret)
     }
 
     pub fn hash(
@@ -189,276 +625,34 @@ impl TorrentMetadata {
         thread_num: u32,
         write_v1: bool,
         write_v2: bool,
-    ) -> io::Result<BencodeValue> {
-        assert!(write_v1 || write_v2);
-        let tasks: SegQueue<HashJob> = SegQueue::new();
-
-        // Fill task queue
-        const MAX_JOB_BYTES: u64 = 1024 * 1024 * 1024; // 1GiB
-        const MERKLE_PIECE_SIZE: u64 = 16 * 1024; // 16KiB
-        for f in &mut self.files {
-            if f.file.metadata.len() == 0 {
-                continue;
-            }
-            let num_tasks = (f.file.metadata.len() - 1) / MAX_JOB_BYTES + 1;
-            // # of piece for each task
-            let mut task_pieces = vec![];
-            for _ in 0..f.hash_v1_piece_count % num_tasks {
-                task_pieces.push(f.hash_v1_piece_count / num_tasks + 1);
-            }
-            for _ in (f.hash_v1_piece_count % num_tasks)..num_tasks {
-                task_pieces.push(f.hash_v1_piece_count / num_tasks);
-            }
-
-            let mut piece_offset = 0;
-            let mut v1_hash = f.hash_v1.as_mut_slice();
-            let mut v2_hash = f.merkle_tree.get_mut(0).unwrap().as_mut_slice();
-            let mut left_merkle_piece = f.merkle_piece_count;
-            for (idx, &this_v1_pieces) in task_pieces.iter().enumerate() {
-                if left_merkle_piece == 0 {
-                    panic!(
-                        "left_merkle_piece==0 {}",
-                        f.file.entry.path().display()
-                    );
-                }
-                let this_v2_pieces = std::cmp::min(
-                    this_v1_pieces * self.piece_factor,
-                    left_merkle_piece,
-                );
-                let right_boundary = std::cmp::min(
-                    (piece_offset + this_v1_pieces) * self.piece_size,
-                    f.file.metadata.len(),
-                );
-                let data_len = right_boundary - piece_offset * self.piece_size;
-
-                let (this_v1_hash, rem_v1_hash) =
-                    v1_hash.split_at_mut((this_v1_pieces * 20) as usize);
-                let (this_v2_hash, rem_v2_hash) =
-                    v2_hash.split_at_mut((this_v2_pieces * 32) as usize);
-                v1_hash = rem_v1_hash;
-                v2_hash = rem_v2_hash;
-
-                let job = HashJob {
-                    file: f.file.entry.path(),
-                    offset: piece_offset * self.piece_size,
-                    data_len,
-                    v1_pieces: this_v1_pieces,
-                    v1_piece_size: self.piece_size,
-                    v1_hash: this_v1_hash,
-                    v1_last_hash_zero_fill: !((idx == task_pieces.len() - 1)
-                        && f.is_last_data_file),
-                    v2_pieces: this_v2_pieces,
-                    v2_hash: this_v2_hash,
-                };
-                debug!("{:?}", job);
-                tasks.push(job);
-
-                piece_offset += this_v1_pieces;
-                left_merkle_piece -= this_v2_pieces;
-            }
+        io: IoMode,
+    ) -> Result<BencodeValue> {
+        if !write_v1 && !write_v2 {
+            return Err(Error::InvalidOption("At least one bep3/bep52".into()));
         }
-
-        progress.hash_begin(self.total_bytes);
-        let piece_factor = self.piece_factor;
-        scope(|s| {
-            let (progress_notify, progress_rx) = mpsc::channel();
-
-            // UI thread
-            s.spawn(|_| {
-                // Rusty magic, moves rx inside without moving progress.
-                let progress_rx = progress_rx;
-                loop {
-                    if let Ok(b) = progress_rx.recv() {
-                        trace!(
-                            "{:?} Received hash_progress: {}",
-                            thread::current().id(),
-                            b
-                        );
-                        progress.hash_progress(b);
-                    } else {
-                        return;
-                    }
-                }
-            });
-
-            // Worker threads
-            for _ in 0..thread_num {
-                let progress = progress_notify.clone();
-                s.spawn(|_| {
-                    let mut byte_count = 0u64;
-                    // move progress into lambda
-                    let progress = progress;
-                    loop {
-                        let job = tasks.pop();
-                        if job.is_none() {
-                            break;
-                        }
-                        let job = job.unwrap();
-                        debug!(
-                            "{:?} takes job {:?}",
-                            thread::current().id(),
-                            job
-                        );
-
-                        // mmap file
-                        let f = File::open(job.file).unwrap();
-                        let mmap =
-                            unsafe { MmapOptions::new().map(&f).unwrap() };
-                        let data = mmap.as_ref();
-
-                        // local vars
-                        // |----|----|----|----| one v1, four v2 pieces
-                        // |-----------|         actual data
-                        // |------------0000000| covered by one v1 hash
-                        // |----|----|-|         covered by 3 v2 hashes
-                        // ^-job.offset        ^-hash_rbound
-                        let hash_rbound =
-                            job.offset + job.v1_pieces * job.v1_piece_size;
-                        let data_rbound = job.offset + job.data_len;
-                        let mut cursor = job.offset;
-
-                        let mut v1_hasher = Sha1::new();
-                        let mut v2_hasher = Sha256::new();
-                        let mut finished_v1_pieces = 0u64;
-                        let mut finished_v2_pieces = 0u64;
-
-                        // loop, in each iter, we either read a full 16kb block
-                        // or reaches the end of the file.
-                        while cursor < data_rbound {
-                            trace!(
-                                "{:?} cursor={} file={}",
-                                thread::current().id(),
-                                cursor,
-                                job.file.display()
-                            );
-                            let bytes =
-                                if cursor + MERKLE_PIECE_SIZE > data_rbound {
-                                    data_rbound - cursor
-                                } else {
-                                    MERKLE_PIECE_SIZE
-                                };
-                            v1_hasher.update(
-                                &data[cursor as usize
-                                    ..(cursor + bytes) as usize],
-                            );
-                            v2_hasher.update(
-                                &data[cursor as usize
-                                    ..(cursor + bytes) as usize],
-                            );
-                            let _ = progress.send(bytes);
-                            byte_count += bytes;
-
-                            if bytes == MERKLE_PIECE_SIZE {
-                                // Finished a v2 piece
-                                let l = (finished_v2_pieces * 32) as usize;
-                                let r =
-                                    ((finished_v2_pieces + 1) * 32) as usize;
-                                job.v2_hash[l..r].copy_from_slice(
-                                    v2_hasher.finalize_reset().as_slice(),
-                                );
-                                finished_v2_pieces += 1;
-
-                                // Also finished a v1 piece
-                                if (finished_v2_pieces % piece_factor) == 0 {
-                                    let l = (finished_v1_pieces * 20) as usize;
-                                    let r = ((finished_v1_pieces + 1) * 20)
-                                        as usize;
-                                    job.v1_hash[l..r].copy_from_slice(
-                                        v1_hasher.finalize_reset().as_slice(),
-                                    );
-                                    finished_v1_pieces += 1;
-                                }
-                            }
-
-                            cursor += bytes;
-                        }
-                        assert!(cursor <= hash_rbound);
-
-                        if cursor % MERKLE_PIECE_SIZE != 0 {
-                            // file end is not aligned to 16kb
-                            // write the last sha256
-                            let l = (finished_v2_pieces * 32) as usize;
-                            let r = ((finished_v2_pieces + 1) * 32) as usize;
-                            job.v2_hash[l..r].copy_from_slice(
-                                v2_hasher.finalize_reset().as_slice(),
-                            );
-                            finished_v2_pieces += 1;
-                        }
-
-                        if cursor != hash_rbound {
-                            // file end is not aligned to v1 hash boundary
-                            // zero fill the remaining v1 hash for gap file
-                            // and write the last sha1
-                            if job.v1_last_hash_zero_fill {
-                                v1_hasher.update(vec![
-                                    0;
-                                    (hash_rbound - cursor)
-                                        as usize
-                                ]);
-                            }
-                            let l = (finished_v1_pieces * 20) as usize;
-                            let r = ((finished_v1_pieces + 1) * 20) as usize;
-                            job.v1_hash[l..r].copy_from_slice(
-                                v1_hasher.finalize_reset().as_slice(),
-                            );
-                            finished_v1_pieces += 1;
-                        }
-
-                        assert_eq!(finished_v1_pieces, job.v1_pieces);
-                        assert_eq!(finished_v2_pieces, job.v2_pieces);
-                    }
-                    debug!(
-                        "{:?} processed {} bytes",
-                        thread::current().id(),
-                        byte_count
-                    );
-                });
-            }
-            drop(progress_notify);
-        })
-        .unwrap();
-        drop(tasks);
-        progress.hash_end();
-
-        // Complete the merkle trees
-        let start = Instant::now();
-        let mut filler_sha256 = vec![vec![0; 32]];
-        for i in 1..50 {
-            // Covers up to 16 EiB. Really?!
-            let mut hasher = Sha256::new();
-            hasher.update(filler_sha256[i - 1].as_slice());
-            hasher.update(filler_sha256[i - 1].as_slice());
-            filler_sha256.push(hasher.finalize().to_vec());
-        }
-
-        for f in &mut self.files {
-            if f.file.metadata.len() == 0 || f.merkle_tree[0].len() == 32 {
-                continue;
-            }
-            for level in 0.. {
-                let src = f.merkle_tree.get(level).unwrap().as_slice();
-                assert!(src.len() != 32);
-                assert!(src.len() % 32 == 0);
-                let mut dst = vec![];
-                for i in 0..src.len() / 64 {
-                    dst.extend_from_slice(
-                        Sha256::digest(&src[i * 64..(i + 1) * 64]).as_slice(),
-                    );
-                }
-                if src.len() > 32 && src.len() % 64 != 0 {
-                    let mut hasher = Sha256::new();
-                    hasher.update(&src[(src.len() / 64) * 64..]);
-                    hasher.update(filler_sha256.get(level).unwrap().as_slice());
-                    dst.extend_from_slice(hasher.finalize().as_slice());
-                }
-                let dst_len = dst.len();
-                f.merkle_tree.push(dst);
-                if dst_len == 32 {
-                    break;
+        if self.file_options.sha1 {
+            for f in &mut self.files {
+                if f.file.symlink_path.is_none() {
+                    f.file_sha1 = Some(FileSha1::default());
                 }
             }
         }
-        info!("Merkle tree built in {}", HumanDuration(start.elapsed()));
+        let tasks =
+            make_hash_jobs(&mut self.files, self.piece_size, self.piece_factor);
+        run_hash_jobs(
+            tasks,
+            self.total_bytes,
+            self.piece_factor,
+            thread_num,
+            io,
+            progress,
+        )?;
+        build_merkle_trees(&mut self.files);
+        let file_sha1s: Vec<Option<Vec<u8>>> = self
+            .files
+            .iter_mut()
+            .map(|f| f.file_sha1.take().map(FileSha1::digest))
+            .collect();
 
         // Assemble info struct
         let mut ret = BTreeMap::<Vec<u8>, BencodeValue>::new();
@@ -493,18 +687,24 @@ impl TorrentMetadata {
                 let file = &self.files[0];
                 info.insert(
                     b"length".to_vec(),
-                    BencodeValue::from(file.file.metadata.len() as i64),
+                    BencodeValue::from(file.file.len() as i64),
+                );
+                insert_file_attrs(
+                    &mut info,
+                    &file.file,
+                    self.file_options,
+                    file_sha1s[0].as_deref(),
                 );
             } else {
                 // Multi file mode
                 let mut files = vec![];
-                for f in &self.files {
+                let mut pad_count = 0;
+                for (f, sha1) in self.files.iter().zip(&file_sha1s) {
                     let mut file = BTreeMap::new();
                     file.insert(
                         b"length".to_vec(),
-                        BencodeValue::from(f.file.metadata.len() as i64),
+                        BencodeValue::from(f.file.len() as i64),
                     );
-                    // assert!(f.file.path_components.len() > 1);
                     assert_eq!(name, f.file.path_components[0]);
                     let mut path_vec = vec![];
                     for idx in 1..f.file.path_components.len() {
@@ -513,26 +713,21 @@ impl TorrentMetadata {
                         ));
                     }
                     file.insert(b"path".to_vec(), BencodeValue::List(path_vec));
+                    insert_file_attrs(
+                        &mut file,
+                        &f.file,
+                        self.file_options,
+                        sha1.as_deref(),
+                    );
                     files.push(BencodeValue::Map(file));
 
                     if f.padding > 0 {
-                        let mut pad_file = BTreeMap::new();
-                        pad_file
-                            .insert(b"attr".to_vec(), BencodeValue::from("p"));
-                        pad_file.insert(
-                            b"length".to_vec(),
-                            BencodeValue::from(f.padding as i64),
-                        );
-                        pad_file.insert(
-                            b"path".to_vec(),
-                            BencodeValue::List(vec![
-                                BencodeValue::from(".pad"),
-                                BencodeValue::from(
-                                    format!("{}", f.padding).as_str(),
-                                ),
-                            ]),
-                        );
-                        files.push(BencodeValue::Map(pad_file));
+                        files.push(pad_file_entry(
+                            f.padding,
+                            pad_count,
+                            self.padding_style,
+                        ));
+                        pad_count += 1;
                     }
                 }
                 info.insert(b"files".to_vec(), BencodeValue::List(files));
@@ -569,9 +764,9 @@ impl TorrentMetadata {
                 let mut inner = BTreeMap::new();
                 inner.insert(
                     b"length".to_vec(),
-                    BencodeValue::from(f.file.metadata.len() as i64),
+                    BencodeValue::from(f.file.len() as i64),
                 );
-                if f.file.metadata.len() != 0 {
+                if !f.file.is_empty() {
                     assert!(f.merkle_tree.last().unwrap().len() == 32);
                     inner.insert(
                         b"pieces root".to_vec(),
@@ -580,13 +775,14 @@ impl TorrentMetadata {
                         ),
                     );
                 }
+                insert_file_attrs(&mut inner, &f.file, self.file_options, None);
                 t.insert(vec![], BencodeValue::Map(inner));
             }
             info.insert(b"file tree".to_vec(), BencodeValue::Map(file_tree));
 
             let mut piece_layers = BTreeMap::new();
             for f in &self.files {
-                if f.file.metadata.len() <= self.piece_size {
+                if f.file.len() <= self.piece_size {
                     continue;
                 }
                 let key = f.merkle_tree.last().unwrap();
@@ -601,67 +797,275 @@ impl TorrentMetadata {
                 BencodeValue::Map(piece_layers),
             );
         }
-        // TODO debug info hash
 
         // Assemble torrent file structure
-        if !self.announces.is_empty() {
-            ret.insert(
-                b"announce".to_vec(),
-                BencodeValue::from(self.announces[0][0].as_str()),
-            );
-        }
-        if self.announces.len() > 1 || self.announces[0].len() > 1 {
-            // BEP 12
-            ret.insert(
-                b"announce-list".to_vec(),
-                BencodeValue::List(
-                    self.announces
-                        .iter()
-                        .map(|tier| {
-                            BencodeValue::List(
-                                tier.iter()
-                                    .map(|a| BencodeValue::from(a.as_str()))
-                                    .collect(),
-                            )
+        insert_trackers(&mut ret, &self.announces, &self.nodes, &self.webseeds);
+        info.extend(self.extra_keys.info.clone());
+        ret.extend(self.extra_keys.root.clone());
+        ret.insert(b"info".to_vec(), BencodeValue::Map(info));
+        Ok(BencodeValue::Map(ret))
+    }
+}
+
+#[cfg(test)]
+mod file_sha1_test {
+    use super::*;
+    use crate::test_util::{data, TempDir};
+
+    #[test]
+    fn parts_out_of_order() {
+        let dir = TempDir::new("file-sha1-parts");
+        let data = data(3000);
+        let path = dir.write("a", &data);
+        let file_sha1 = FileSha1::default();
+        let failed = AtomicBool::new(false);
+        // (part, got the SHA-1 while hashing)
+        let hashed = Mutex::new(vec![]);
+        let hash_part = |idx: usize| {
+            let mut reader = FileReader::new(IoMode::Read);
+            let offset = idx as u64 * 1000;
+            file_sha1
+                .hash_part(
+                    idx,
+                    &failed,
+                    &mut reader,
+                    &path,
+                    offset,
+                    1000,
+                    |reader, mut sha1| {
+                        hashed.lock().unwrap().push((idx, sha1.is_some()));
+                        reader.read(&path, offset, 1000, |data| {
+                            if let Some(sha1) = &mut sha1 {
+                                sha1.update(data);
+                            }
                         })
-                        .collect(),
-                ),
-            );
+                    },
+                )
+                .unwrap();
+        };
+        scope(|s| {
+            s.spawn(|_| hash_part(2));
+            // The pieces of part 2 don't wait for the earlier parts.
+            while hashed.lock().unwrap().is_empty() {
+                thread::yield_now();
+            }
+            hash_part(0);
+            hash_part(1);
+        })
+        .unwrap();
+        assert_eq!(
+            hashed.into_inner().unwrap(),
+            vec![(2, false), (0, true), (1, true)]
+        );
+        assert_eq!(file_sha1.digest(), Sha1::digest(&data).to_vec());
+    }
+}
+
+#[cfg(test)]
+mod hash_job_test {
+    use super::*;
+    use crate::test_util::{data_file, TempDir};
+
+    const MIB: u64 = 1024 * 1024;
+
+    fn sparse_file(dir: &TempDir, name: &str, len: u64) -> DataFile {
+        let path = dir.path().join(name);
+        std::fs::File::create(&path).unwrap().set_len(len).unwrap();
+        data_file(path, &[name])
+    }
+
+    #[test]
+    fn large_piece_jobs() {
+        let piece_size = 64 * MIB;
+        let piece_factor = piece_size / MERKLE_PIECE_SIZE;
+        let len = 3 * 1024 * MIB + 5;
+        let dir = TempDir::new("large-piece-jobs");
+        let f = sparse_file(&dir, "f", len);
+        let mut files = vec![FileMetadata::new(f, piece_size)];
+        assert_eq!(files[0].hash_v1_piece_count, 49);
+        assert_eq!(files[0].padding, piece_size - 5);
+
+        let tasks = make_hash_jobs(&mut files, piece_size, piece_factor);
+        let mut jobs = vec![];
+        while let Some(job) = tasks.pop() {
+            jobs.push((job.offset, job.data_len, job.v1_pieces, job.v2_pieces));
         }
-        if !self.nodes.is_empty() {
-            // BEP 5
-            ret.insert(
-                b"nodes".to_vec(),
-                BencodeValue::List(
-                    self.nodes
-                        .iter()
-                        .map(|(host, port)| {
-                            BencodeValue::List(vec![
-                                BencodeValue::from(host.as_str()),
-                                BencodeValue::from(*port as i64),
-                            ])
-                        })
-                        .collect(),
+        assert_eq!(
+            jobs,
+            vec![
+                (0, 13 * piece_size, 13, 13 * piece_factor),
+                (13 * piece_size, 12 * piece_size, 12, 12 * piece_factor),
+                (25 * piece_size, 12 * piece_size, 12, 12 * piece_factor),
+                (
+                    37 * piece_size,
+                    len - 37 * piece_size,
+                    12,
+                    11 * piece_factor + 1
                 ),
-            );
-        }
-        if !self.webseeds.is_empty() {
-            // BEP 19
-            ret.insert(
-                b"url-list".to_vec(),
-                if self.webseeds.len() == 1 {
-                    BencodeValue::from(self.webseeds[0].as_str())
+            ]
+        );
+    }
+
+    #[test]
+    fn file_sha1_in_order() {
+        let data = (0..3000u32).map(|x| x as u8).collect::<Vec<_>>();
+        let chunks: Vec<&[u8]> = data.chunks(1000).collect();
+        let file_sha1 = FileSha1::default();
+        let failed = AtomicBool::new(false);
+        scope(|s| {
+            // Start the last job first
+            for (idx, chunk) in chunks.iter().enumerate().rev() {
+                let (file_sha1, failed) = (&file_sha1, &failed);
+                s.spawn(move |_| {
+                    let mut sha1 = file_sha1.take(idx, failed).unwrap();
+                    sha1.update(chunk);
+                    file_sha1.put(sha1);
+                });
+            }
+        })
+        .unwrap();
+        assert_eq!(file_sha1.digest(), Sha1::digest(&data).to_vec());
+    }
+}
+
+#[cfg(test)]
+mod padding_test {
+    use super::*;
+
+    use crate::test_util::{data_file, TempDir};
+
+    // Hashes files of the given sizes as a torrent named `t`, or in single
+    // file mode if `single_file`.
+    fn hash(
+        dir: &TempDir,
+        sizes: &[(&str, usize)],
+        single_file: bool,
+        write_v2: bool,
+        options: PaddingOptions,
+    ) -> BencodeValue {
+        let files = sizes
+            .iter()
+            .map(|&(name, len)| {
+                let path = dir.write(name, &vec![1u8; len]);
+                if single_file {
+                    data_file(path, &[name])
                 } else {
-                    BencodeValue::List(
-                        self.webseeds
-                            .iter()
-                            .map(|s| BencodeValue::from(s.as_str()))
-                            .collect(),
-                    )
-                },
-            );
-        }
-        ret.insert(b"info".to_vec(), BencodeValue::Map(info));
-        Ok(BencodeValue::Map(ret))
+                    data_file(path, &["t", name])
+                }
+            })
+            .collect();
+        let walked_dir = WalkedDir {
+            canonical_path: dir.path().to_path_buf(),
+            prefix: dir.path().to_path_buf(),
+            files,
+        };
+        let mut meta = TorrentMetadata::new(
+            vec![vec!["http://t/".into()]],
+            vec![],
+            false,
+            Some(16384),
+            vec![],
+            FileOptions::default(),
+            options,
+            ExtraKeys::default(),
+            walked_dir,
+        )
+        .unwrap();
+        let mut progress = ProgressIndicator::new(true);
+        meta.hash(&mut progress, 1, true, write_v2, IoMode::Read)
+            .unwrap()
+    }
+
+    // Paths and lengths in the v1 file list of a v1-only torrent
+    fn v1_files(
+        case: &str,
+        sizes: &[(&str, usize)],
+        options: PaddingOptions,
+    ) -> Vec<(String, i64)> {
+        let dir = TempDir::new(case);
+        let root = hash(&dir, sizes, false, false, options);
+        let files = root.get(b"info").unwrap().get(b"files").unwrap();
+        files
+            .as_list()
+            .unwrap()
+            .iter()
+            .map(|f| {
+                let path = f.get(b"path").unwrap().as_list().unwrap();
+                let path: Vec<&str> =
+                    path.iter().map(|c| c.as_str().unwrap()).collect();
+                let len = f.get(b"length").unwrap().as_integer().unwrap();
+                (path.join("/"), len)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn no_trailing_padding() {
+        let sizes = [("a", 5), ("b", 3), ("c", 0), ("d", 0)];
+        assert_eq!(
+            v1_files("no-trailing-padding", &sizes, PaddingOptions::default()),
+            vec![
+                ("a".into(), 5),
+                (".pad/16379".into(), 16379),
+                ("b".into(), 3),
+                ("c".into(), 0),
+                ("d".into(), 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn pad_last() {
+        let sizes = [("a", 5), ("b", 3), ("c", 0)];
+        let options = PaddingOptions {
+            pad_last: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            v1_files("pad-last", &sizes, options),
+            vec![
+                ("a".into(), 5),
+                (".pad/16379".into(), 16379),
+                ("b".into(), 3),
+                (".pad/16381".into(), 16381),
+                ("c".into(), 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn legacy_style() {
+        let sizes = [("a", 5), ("b", 3)];
+        let options = PaddingOptions {
+            pad_last: true,
+            style: PadStyle::Legacy,
+            ..Default::default()
+        };
+        assert_eq!(
+            v1_files("legacy-style", &sizes, options),
+            vec![
+                ("a".into(), 5),
+                ("_____padding_file_0_".into(), 16379),
+                ("b".into(), 3),
+                ("_____padding_file_1_".into(), 16381),
+            ]
+        );
+    }
+
+    #[test]
+    fn single_file_pad_last() {
+        // Single file mode has no file list to hold a padding file.
+        let dir = TempDir::new("single-file-pad-last");
+        let options = PaddingOptions {
+            pad_last: true,
+            ..Default::default()
+        };
+        let root = hash(&dir, &[("a", 5)], true, true, options);
+        let info = root.get(b"info").unwrap();
+        assert!(info.get(b"files").is_none());
+        assert_eq!(
+            info.get(b"pieces").unwrap().as_bytes().unwrap(),
+            Sha1::digest(&[1u8; 5]).as_slice()
+        );
     }
 }
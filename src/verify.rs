@@ -0,0 +1,343 @@
+use crate::dirwalker::DataFile;
+use crate::error::{Error, Result};
+use crate::metainfo::{MetaFile, MetaInfo};
+use crate::progress::ProgressIndicator;
+use crate::reader::{FileReader, IoMode};
+use crate::torrent_meta::Bep3Hasher;
+use crate::torrent_meta_v2::*;
+
+use log::*;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub enum FileStatus {
+    Ok,
+    Missing,
+    SizeMismatch(u64),
+    Corrupted,
+}
+
+/// Verification result of one (non-padding) file of the torrent.
+pub struct FileReport {
+    pub path: PathBuf,
+    pub status: FileStatus,
+    // Indices of BEP3 pieces overlapping this file that failed
+    pub bad_v1_pieces: Vec<u64>,
+    // The BEP52 pieces root of this file doesn't match
+    pub bad_v2_root: bool,
+    // Indices of BEP52 pieces of this file that failed, checked against the
+    // piece layer. Files of one piece only have the pieces root.
+    pub bad_v2_pieces: Vec<u64>,
+}
+
+// Where a file of the torrent lives in the v1 piece stream.
+struct Placement<'a> {
+    meta: &'a MetaFile,
+    path: PathBuf,
+    offset: u64,
+    present: bool,
+}
+
+// HashJob cuts pieces at file boundaries, which only reproduces the BEP3
+// hashes if every file starts on a piece boundary (i.e. the torrent is
+// padded). Anything else goes through the sequential Bep3Hasher.
+fn v1_aligned(meta: &MetaInfo, files: &[Placement]) -> bool {
+    let pl = meta.piece_length;
+    pl >= 16 * 1024
+        && pl.is_power_of_two()
+        && files.iter().all(|f| {
+            f.meta.is_padding() || f.meta.length == 0 || f.offset % pl == 0
+        })
+}
+
+fn hash_v1_sequential(
+    meta: &MetaInfo,
+    files: &[Placement],
+    io: IoMode,
+    progress: &mut ProgressIndicator,
+) -> Result<Vec<Vec<u8>>> {
+    let mut hasher = Bep3Hasher::new(meta.piece_length);
+    let mut reader = FileReader::new(io);
+    for f in files {
+        if f.meta.is_padding() || !f.present || f.meta.length == 0 {
+            // Missing data is hashed as zeros, its pieces fail anyway.
+            hasher.visit_zeros(f.meta.length, progress);
+        } else {
+            debug!("Hashing {}...", f.path.display());
+            reader.read(&f.path, 0, f.meta.length, |data| {
+                hasher.visit_file(data, progress)
+            })?;
+        }
+    }
+    hasher.visit_end();
+    Ok(hasher.hashes)
+}
+
+/// Re-hashes the data under `data_path` (the file or folder the torrent
+/// was created from) and compares it against the torrent.
+pub fn verify(
+    meta: &MetaInfo,
+    data_path: &Path,
+    thread_num: u32,
+    io: IoMode,
+    progress: &mut ProgressIndicator,
+) -> Result<Vec<FileReport>> {
+    // Locate the files on disk
+    let mut placements = vec![];
+    let mut reports = vec![];
+    let mut offset = 0;
+    for f in &meta.files {
+        let mut path = data_path.to_path_buf();
+        path.extend(&f.path);
+        let mut present = false;
+        if f.is_symlink() {
+            let status = match fs::symlink_metadata(&path) {
+                Ok(m) if m.file_type().is_symlink() => FileStatus::Ok,
+                _ => FileStatus::Missing,
+            };
+            reports.push(FileReport {
+                path: path.clone(),
+                status,
+                bad_v1_pieces: vec![],
+                bad_v2_root: false,
+                bad_v2_pieces: vec![],
+            });
+        } else if !f.is_padding() {
+            let status = match fs::metadata(&path) {
+                Ok(m) if m.is_file() && m.len() == f.length => {
+                    present = true;
+                    FileStatus::Ok
+                }
+                Ok(m) if m.is_file() => FileStatus::SizeMismatch(m.len()),
+                _ => FileStatus::Missing,
+            };
+            reports.push(FileReport {
+                path: path.clone(),
+                status,
+                bad_v1_pieces: vec![],
+                bad_v2_root: false,
+                bad_v2_pieces: vec![],
+            });
+        }
+        placements.push(Placement {
+            meta: f,
+            path,
+            offset,
+            present,
+        });
+        offset += f.length;
+    }
+    let data_files: Vec<&Placement> =
+        placements.iter().filter(|p| !p.meta.is_padding()).collect();
+
+    // Hash the present files using the v2 worker pool
+    let pl = meta.piece_length;
+    let v1_pool = meta.has_v1() && v1_aligned(meta, &placements);
+    if meta.has_v2() || v1_pool {
+        let total = meta.total_length();
+        let mut files = vec![];
+        // index into data_files and reports
+        let mut hashed = vec![];
+        for (i, p) in data_files.iter().enumerate() {
+            if !p.present || p.meta.length == 0 {
+                continue;
+            }
+            let mut fm = FileMetadata::new(
+                DataFile {
+                    path: p.path.clone(),
+                    path_components: p.meta.path.clone(),
+                    metadata: fs::metadata(&p.path)
+                        .map_err(|e| Error::io(&p.path, e))?,
+                    symlink_path: None,
+                },
+                pl,
+            );
+            // Bytes after the file up to the piece boundary are padding,
+            // unless this is the end of the torrent.
+            fm.is_last_data_file = p.offset + p.meta.length == total;
+            files.push(fm);
+            hashed.push(i);
+        }
+        let hashed_bytes = files.iter().map(|f| f.file.len()).sum();
+        let piece_factor = pl / (16 * 1024);
+        let tasks = make_hash_jobs(&mut files, pl, piece_factor);
+        run_hash_jobs(
+            tasks,
+            hashed_bytes,
+            piece_factor,
+            thread_num,
+            io,
+            progress,
+        )?;
+        build_merkle_trees(&mut files);
+
+        for (fm, &i) in files.iter().zip(hashed.iter()) {
+            let (report, p) = (&mut reports[i], data_files[i]);
+            if meta.has_v2() {
+                let root = fm.merkle_tree.last().unwrap();
+                report.bad_v2_root =
+                    p.meta.pieces_root.as_deref() != Some(root.as_slice());
+                let layer = if p.meta.length > pl {
+                    let level = piece_factor.trailing_zeros() as usize;
+                    fm.merkle_tree[level].as_slice()
+                } else {
+                    root.as_slice()
+                };
+                // A missing piece layer fails every piece.
+                let expected = match &p.meta.pieces_root {
+                    Some(r) if p.meta.length > pl => meta
+                        .piece_layers
+                        .get(r)
+                        .map(Vec::as_slice)
+                        .unwrap_or_default(),
+                    Some(r) => r.as_slice(),
+                    None => &[],
+                };
+                for (j, h) in layer.chunks(32).enumerate() {
+                    let l = j * 32;
+                    if expected.get(l..l + 32) != Some(h) {
+                        report.bad_v2_pieces.push(j as u64);
+                    }
+                }
+            }
+            if v1_pool {
+                let pieces = meta.pieces.as_ref().unwrap();
+                let first = p.offset / pl;
+                for (j, h) in fm.hash_v1.chunks(20).enumerate() {
+                    let idx = first + j as u64;
+                    let l = (idx * 20) as usize;
+                    if h != &pieces[l..l + 20] {
+                        report.bad_v1_pieces.push(idx);
+                    }
+                }
+            }
+        }
+    }
+
+    // Sequential BEP3 hashing for unpadded torrents
+    if meta.has_v1() && !v1_pool {
+        progress.hash_begin(meta.total_length());
+        let hashes = hash_v1_sequential(meta, &placements, io, progress)?;
+        progress.hash_end();
+        let pieces = meta.pieces.as_ref().unwrap();
+        for (idx, h) in hashes.iter().enumerate() {
+            let idx = idx as u64;
+            let l = (idx * 20) as usize;
+            if h.as_slice() == &pieces[l..l + 20] {
+                continue;
+            }
+            let (l, r) = (idx * pl, (idx + 1) * pl);
+            for (report, p) in reports.iter_mut().zip(data_files.iter()) {
+                if p.meta.length > 0
+                    && p.offset < r
+                    && l < p.offset + p.meta.length
+                {
+                    report.bad_v1_pieces.push(idx);
+                }
+            }
+        }
+    }
+
+    for r in &mut reports {
+        if matches!(r.status, FileStatus::Ok)
+            && (r.bad_v2_root
+                || !r.bad_v1_pieces.is_empty()
+                || !r.bad_v2_pieces.is_empty())
+        {
+            r.status = FileStatus::Corrupted;
+        }
+    }
+    Ok(reports)
+}
+
+#[cfg(test)]
+mod verify_test {
+    use super::*;
+    use crate::test_util::{data, TempDir};
+    use crate::TorrentBuilder;
+
+    const KIB: usize = 1024;
+
+    fn create(builder: TorrentBuilder) -> MetaInfo {
+        let torrent = builder
+            .announce("http://tracker/")
+            .piece_size(16 * KIB as u64)
+            .build()
+            .unwrap();
+        MetaInfo::from_bytes(&torrent.to_bytes()).unwrap()
+    }
+
+    fn check(meta: &MetaInfo, path: &Path) -> Vec<FileReport> {
+        let mut progress = ProgressIndicator::new(true);
+        verify(meta, path, 2, IoMode::Read, &mut progress).unwrap()
+    }
+
+    fn corrupt(path: &Path, offset: usize) {
+        let mut data = fs::read(path).unwrap();
+        data[offset] ^= 1;
+        fs::write(path, data).unwrap();
+    }
+
+    #[test]
+    fn hybrid() {
+        let dir = TempDir::new("verify-hybrid");
+        let a = dir.write("d/a", &data(50 * KIB));
+        dir.write("d/b", &data(100));
+        let root = dir.path().join("d");
+        let meta = create(TorrentBuilder::new(&root));
+        let reports = check(&meta, &root);
+        assert!(reports.iter().all(|r| matches!(r.status, FileStatus::Ok)));
+
+        corrupt(&a, 40 * KIB);
+        fs::remove_file(dir.path().join("d/b")).unwrap();
+        let reports = check(&meta, &root);
+        assert!(matches!(reports[0].status, FileStatus::Corrupted));
+        assert_eq!(reports[0].bad_v1_pieces, vec![2]);
+        assert_eq!(reports[0].bad_v2_pieces, vec![2]);
+        assert!(reports[0].bad_v2_root);
+        assert!(matches!(reports[1].status, FileStatus::Missing));
+    }
+
+    #[test]
+    fn v1_unpadded() {
+        let dir = TempDir::new("verify-v1");
+        dir.write("d/a", &data(20 * KIB));
+        let b = dir.write("d/b", &data(30 * KIB));
+        let root = dir.path().join("d");
+        let meta = create(TorrentBuilder::new(&root).v2(false).padding(false));
+        assert!(check(&meta, &root)
+            .iter()
+            .all(|r| matches!(r.status, FileStatus::Ok)));
+
+        // The second piece spans both files.
+        corrupt(&b, 0);
+        let reports = check(&meta, &root);
+        assert_eq!(reports[0].bad_v1_pieces, vec![1]);
+        assert_eq!(reports[1].bad_v1_pieces, vec![1]);
+        assert!(reports[1].bad_v2_pieces.is_empty());
+    }
+
+    #[test]
+    fn legacy_padding() {
+        use crate::PadStyle;
+
+        let dir = TempDir::new("verify-legacy");
+        dir.write("d/a", &data(20 * KIB));
+        let b = dir.write("d/b", &data(30 * KIB));
+        let root = dir.path().join("d");
+        let mut meta =
+            create(TorrentBuilder::new(&root).pad_style(PadStyle::Legacy));
+        // Older clients only name the padding files.
+        for f in &mut meta.files {
+            f.attr.clear();
+        }
+        let reports = check(&meta, &root);
+        assert_eq!(reports.len(), 2);
+        assert!(reports.iter().all(|r| matches!(r.status, FileStatus::Ok)));
+
+        corrupt(&b, 0);
+        let reports = check(&meta, &root);
+        assert!(matches!(reports[0].status, FileStatus::Ok));
+        assert_eq!(reports[1].bad_v1_pieces, vec![2]);
+    }
+}